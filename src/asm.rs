@@ -0,0 +1,571 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::sourcemap::{SourceLocation, SourceMap};
+
+/// Address programs are loaded at, see `Chip8::load_rom`
+pub const BASE: usize = 0x200;
+const MAX_INCLUDE_DEPTH: usize = 16;
+// Constants referring to constants, deeper is most likely a cycle
+const MAX_CONSTANT_DEPTH: usize = 64;
+
+#[derive(Debug)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+/// An assembled program, to be loaded at `BASE`
+pub struct Assembly {
+    pub binary: Vec<u8>,
+    /// Address of every label
+    pub symbols: BTreeMap<String, usize>,
+    pub source_map: SourceMap,
+}
+
+impl Assembly {
+    /// One `ADDR NAME` line per label, sorted by address
+    pub fn symbol_map(&self) -> String {
+        let mut symbols: Vec<(&String, &usize)> = self.symbols.iter().collect();
+        symbols.sort_by_key(|(name, addr)| (**addr, name.to_string()));
+        symbols
+            .iter()
+            .map(|(name, addr)| format!("0x{:04x} {}\n", addr, name))
+            .collect()
+    }
+}
+
+enum Item {
+    Instruction(String, Vec<String>),
+    Bytes(Vec<String>),
+    Words(Vec<String>),
+}
+
+struct Statement {
+    file: String,
+    // Absolute path of `file` for the source map, editors resolve it from anywhere
+    source_path: String,
+    line: usize,
+    addr: usize,
+    item: Item,
+}
+
+enum Symbol {
+    Label(usize),
+    // Constants are evaluated when used, they may refer to labels defined later
+    Constant(String),
+}
+
+/// Assemble the file at `path`. The syntax is the one of the disassembler listings: one
+/// instruction per line (`LD V0, 0x1e`, `DRW V0, V1, 4`), `name:` labels, `name equ value`
+/// constants, `db`/`dw` data, `include "file"` and `;` comments. Numbers are decimal, `0x` or
+/// `#` hex, or `0b` binary, and can be added or subtracted.
+pub fn assemble_file(path: &str) -> Result<Assembly, AsmError> {
+    let mut assembler = Assembler {
+        statements: vec![],
+        symbols: HashMap::new(),
+        addr: BASE,
+    };
+    assembler.read(Path::new(path), None, 0)?;
+    assembler.encode()
+}
+
+struct Assembler {
+    statements: Vec<Statement>,
+    symbols: HashMap<String, Symbol>,
+    addr: usize,
+}
+
+impl Assembler {
+    // First pass: parse the file and its includes, laying out labels
+    fn read(
+        &mut self,
+        path: &Path,
+        included_from: Option<(&str, usize)>,
+        depth: usize,
+    ) -> Result<(), AsmError> {
+        let file = path.to_string_lossy().into_owned();
+        let text = fs::read_to_string(path).map_err(|e| {
+            let (from, line) = included_from.unwrap_or((&file, 0));
+            error(from, line, format!("Unable to read {}: {}", file, e))
+        })?;
+        let source_path =
+            fs::canonicalize(path).map_or(file.clone(), |path| path.to_string_lossy().into_owned());
+
+        for (number, line) in text.lines().enumerate() {
+            let line_number = number + 1;
+            let err = |message: String| error(&file, line_number, message);
+            let mut line = strip_comment(line).trim();
+
+            // Any number of labels can precede a statement
+            while let Some((label, rest)) = split_label(line) {
+                self.define(label, Symbol::Label(self.addr)).map_err(err)?;
+                line = rest.trim();
+            }
+            if line.is_empty() {
+                continue;
+            }
+
+            let (keyword, operands) = match line.split_once(char::is_whitespace) {
+                Some((keyword, operands)) => (keyword, operands.trim()),
+                None => (line, ""),
+            };
+            let keyword = keyword.to_ascii_uppercase();
+
+            // `name equ value`
+            if let Some((name, value)) = split_constant(line) {
+                self.define(name, Symbol::Constant(value.to_string()))
+                    .map_err(err)?;
+                continue;
+            }
+
+            let item = match keyword.as_str() {
+                "INCLUDE" => {
+                    if depth >= MAX_INCLUDE_DEPTH {
+                        return Err(err("Includes nested too deep".to_string()));
+                    }
+                    let name = operands.trim_matches('"');
+                    let included = path
+                        .parent()
+                        .map_or(PathBuf::from(name), |dir| dir.join(name));
+                    self.read(&included, Some((&file, line_number)), depth + 1)?;
+                    continue;
+                }
+                "DB" => Item::Bytes(split_operands(operands)),
+                "DW" => Item::Words(split_operands(operands)),
+                _ => Item::Instruction(keyword, split_operands(operands)),
+            };
+            let size = match &item {
+                Item::Instruction(mnemonic, operands) => instruction_size(mnemonic, operands),
+                Item::Bytes(values) => values.iter().map(|value| data_size(value)).sum(),
+                Item::Words(values) => values.len() * 2,
+            };
+            self.statements.push(Statement {
+                file: file.clone(),
+                source_path: source_path.clone(),
+                line: line_number,
+                addr: self.addr,
+                item,
+            });
+            self.addr += size;
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str, symbol: Symbol) -> Result<(), String> {
+        if !is_identifier(name) {
+            return Err(format!("Invalid symbol name {}", name));
+        }
+        if is_reserved(name) {
+            return Err(format!("{} is reserved", name));
+        }
+        if self.symbols.insert(name.to_string(), symbol).is_some() {
+            return Err(format!("{} is already defined", name));
+        }
+        Ok(())
+    }
+
+    // Second pass: encode every statement now that all labels are known
+    fn encode(self) -> Result<Assembly, AsmError> {
+        let mut binary = vec![];
+        let mut source_map = vec![];
+        for statement in &self.statements {
+            let err = |message: String| error(&statement.file, statement.line, message);
+            let value = |text: &str| self.evaluate(text, 0).map_err(err);
+            match &statement.item {
+                Item::Instruction(mnemonic, operands) => {
+                    let op_codes = self.instruction(mnemonic, operands).map_err(err)?;
+                    for op_code in op_codes {
+                        binary.extend_from_slice(&op_code.to_be_bytes());
+                    }
+                    source_map.push(SourceLocation {
+                        addr: statement.addr,
+                        file: statement.source_path.clone(),
+                        line: statement.line,
+                    });
+                }
+                Item::Bytes(values) => {
+                    for text in values {
+                        match string_literal(text) {
+                            Some(string) => binary.extend_from_slice(string.as_bytes()),
+                            None => binary.push(byte(value(text)?).map_err(err)?),
+                        }
+                    }
+                }
+                Item::Words(values) => {
+                    for text in values {
+                        let word = value(text)?;
+                        if !(-0x8000..=0xFFFF).contains(&word) {
+                            return Err(err(format!("{} doesn't fit in a word", text)));
+                        }
+                        binary.extend_from_slice(&(word as u16).to_be_bytes());
+                    }
+                }
+            }
+        }
+
+        let symbols = self
+            .symbols
+            .iter()
+            .filter_map(|(name, symbol)| match symbol {
+                Symbol::Label(addr) => Some((name.clone(), *addr)),
+                Symbol::Constant(_) => None,
+            })
+            .collect();
+        Ok(Assembly {
+            binary,
+            symbols,
+            source_map: SourceMap::new(source_map),
+        })
+    }
+
+    fn instruction(&self, mnemonic: &str, operands: &[String]) -> Result<Vec<u16>, String> {
+        let operands: Vec<&str> = operands.iter().map(String::as_str).collect();
+        let value = |text: &str| self.evaluate(text, 0);
+        let addr = |text: &str| -> Result<u16, String> {
+            let addr = value(text)?;
+            if !(0..=0xFFF).contains(&addr) {
+                return Err(format!("Address {} out of range", text));
+            }
+            Ok(addr as u16)
+        };
+        let kk = |text: &str| value(text).and_then(byte).map(u16::from);
+        let nibble = |text: &str| -> Result<u16, String> {
+            let n = value(text)?;
+            if !(0..=0xF).contains(&n) {
+                return Err(format!("{} doesn't fit in a nibble", text));
+            }
+            Ok(n as u16)
+        };
+        let x = |register: usize| (register as u16) << 8;
+        let y = |register: usize| (register as u16) << 4;
+
+        let op_code = match (mnemonic, operands.as_slice()) {
+            ("CLS", []) => 0x00E0,
+            ("RET", []) => 0x00EE,
+            ("SCD", [n]) => 0x00C0 | nibble(n)?,
+            ("SCU", [n]) => 0x00D0 | nibble(n)?,
+            ("SCR", []) => 0x00FB,
+            ("SCL", []) => 0x00FC,
+            ("EXIT", []) => 0x00FD,
+            ("LOW", []) => 0x00FE,
+            ("HIGH", []) => 0x00FF,
+            ("SYS", [nnn]) => addr(nnn)?,
+            ("JP", [nnn]) => 0x1000 | addr(nnn)?,
+            ("JP", [vx, nnn]) => {
+                let vx = register(vx).ok_or("JP takes V0 and an address")?;
+                let nnn = addr(nnn)?;
+                // SUPER-CHIP's BXNN jumps to XNN + VX
+                if vx != 0 && vx != (nnn >> 8) as usize {
+                    return Err(format!(
+                        "JP V{:X} needs an address in 0x{:X}00-0x{:X}ff",
+                        vx, vx, vx
+                    ));
+                }
+                0xB000 | nnn
+            }
+            ("CALL", [nnn]) => 0x2000 | addr(nnn)?,
+            ("SE", [vx, operand]) | ("SNE", [vx, operand]) => {
+                let vx = register(vx).ok_or(format!("{} takes VX first", mnemonic))?;
+                let skip_if_equal = mnemonic == "SE";
+                match register(operand) {
+                    Some(vy) if skip_if_equal => 0x5000 | x(vx) | y(vy),
+                    Some(vy) => 0x9000 | x(vx) | y(vy),
+                    None if skip_if_equal => 0x3000 | x(vx) | kk(operand)?,
+                    None => 0x4000 | x(vx) | kk(operand)?,
+                }
+            }
+            ("ADD", [target, operand]) => {
+                match (target.to_ascii_uppercase().as_str(), register(operand)) {
+                    ("I", Some(vx)) => 0xF01E | x(vx),
+                    (_, vy) => {
+                        let vx = register(target).ok_or("ADD takes VX or I first")?;
+                        match vy {
+                            Some(vy) => 0x8004 | x(vx) | y(vy),
+                            None => 0x7000 | x(vx) | kk(operand)?,
+                        }
+                    }
+                }
+            }
+            ("OR", [vx, vy])
+            | ("AND", [vx, vy])
+            | ("XOR", [vx, vy])
+            | ("SUB", [vx, vy])
+            | ("SUBN", [vx, vy])
+            | ("SHR", [vx, vy])
+            | ("SHL", [vx, vy]) => {
+                let n = match mnemonic {
+                    "OR" => 0x1,
+                    "AND" => 0x2,
+                    "XOR" => 0x3,
+                    "SUB" => 0x5,
+                    "SHR" => 0x6,
+                    "SUBN" => 0x7,
+                    _ => 0xE,
+                };
+                let vx = register(vx).ok_or(format!("{} takes two registers", mnemonic))?;
+                let vy = register(vy).ok_or(format!("{} takes two registers", mnemonic))?;
+                0x8000 | x(vx) | y(vy) | n
+            }
+            // The shift source defaults to VX itself
+            ("SHR", [vx]) | ("SHL", [vx]) => {
+                let vx = register(vx).ok_or(format!("{} takes a register", mnemonic))?;
+                let n = if mnemonic == "SHR" { 0x6 } else { 0xE };
+                0x8000 | x(vx) | y(vx) | n
+            }
+            ("RND", [vx, byte]) => {
+                0xC000 | x(register(vx).ok_or("RND takes VX first")?) | kk(byte)?
+            }
+            ("DRW", [vx, vy, n]) => {
+                let vx = register(vx).ok_or("DRW takes two registers and a nibble")?;
+                let vy = register(vy).ok_or("DRW takes two registers and a nibble")?;
+                0xD000 | x(vx) | y(vy) | nibble(n)?
+            }
+            ("SKP", [vx]) => 0xE09E | x(register(vx).ok_or("SKP takes a register")?),
+            ("SKNP", [vx]) => 0xE0A1 | x(register(vx).ok_or("SKNP takes a register")?),
+            ("PLANE", [n]) => 0xF001 | nibble(n)? << 8,
+            ("AUDIO", []) => 0xF002,
+            ("PITCH", [vx]) => 0xF03A | x(register(vx).ok_or("PITCH takes a register")?),
+            ("LD", [target, source]) => return self.ld(target, source),
+            _ => {
+                return Err(format!(
+                    "Unknown instruction {} {}",
+                    mnemonic,
+                    operands.join(", ")
+                ))
+            }
+        };
+        Ok(vec![op_code])
+    }
+
+    fn ld(&self, target: &str, source: &str) -> Result<Vec<u16>, String> {
+        let x = |register: usize| (register as u16) << 8;
+        let upper = |text: &str| text.to_ascii_uppercase();
+        let op_code = match (register(target), upper(target).as_str()) {
+            (Some(vx), _) => match (register(source), upper(source).as_str()) {
+                (Some(vy), _) => 0x8000 | x(vx) | (vy as u16) << 4,
+                (None, "DT") => 0xF007 | x(vx),
+                (None, "K") => 0xF00A | x(vx),
+                (None, "[I]") => 0xF065 | x(vx),
+                (None, "R") => 0xF085 | x(vx),
+                _ => 0x6000 | x(vx) | u16::from(byte(self.evaluate(source, 0)?)?),
+            },
+            (None, "I") => {
+                let (long, source) = match strip_keyword(source, "long") {
+                    Some(source) => (true, source),
+                    None => (false, source),
+                };
+                let addr = self.evaluate(source, 0)?;
+                if long {
+                    if !(0..=0xFFFF).contains(&addr) {
+                        return Err(format!("Address {} out of range", source));
+                    }
+                    return Ok(vec![0xF000, addr as u16]);
+                }
+                if !(0..=0xFFF).contains(&addr) {
+                    return Err(format!("Address {} out of range, use LD I, long", source));
+                }
+                0xA000 | addr as u16
+            }
+            (None, "DT") => 0xF015 | x(register(source).ok_or("LD DT takes a register")?),
+            (None, "ST") => 0xF018 | x(register(source).ok_or("LD ST takes a register")?),
+            (None, "F") => 0xF029 | x(register(source).ok_or("LD F takes a register")?),
+            (None, "HF") => 0xF030 | x(register(source).ok_or("LD HF takes a register")?),
+            (None, "B") => 0xF033 | x(register(source).ok_or("LD B takes a register")?),
+            (None, "R") => 0xF075 | x(register(source).ok_or("LD R takes a register")?),
+            (None, "[I]") => match (register(source), register_range(source)) {
+                (Some(vx), _) => 0xF055 | x(vx),
+                (None, Some((vx, vy))) => 0x5002 | x(vx) | (vy as u16) << 4,
+                _ => return Err("LD [I] takes a register or a range".to_string()),
+            },
+            _ => match (register_range(target), upper(source).as_str()) {
+                (Some((vx, vy)), "[I]") => 0x5003 | x(vx) | (vy as u16) << 4,
+                _ => return Err(format!("Unknown instruction LD {}, {}", target, source)),
+            },
+        };
+        Ok(vec![op_code])
+    }
+
+    // Terms added or subtracted left to right
+    fn evaluate(&self, text: &str, depth: usize) -> Result<i64, String> {
+        if depth > MAX_CONSTANT_DEPTH {
+            return Err(format!("{} is defined in a cycle", text));
+        }
+        let text = text.trim();
+        let mut total = 0;
+        let mut sign = 1;
+        let mut term_start = 0;
+        let mut expect_term = true;
+        for (i, c) in text
+            .char_indices()
+            .chain(std::iter::once((text.len(), '+')))
+        {
+            if (c == '+' || c == '-') && !expect_term {
+                total += sign * self.term(text[term_start..i].trim(), depth)?;
+                sign = if c == '-' { -1 } else { 1 };
+                term_start = i + 1;
+                expect_term = true;
+            } else if c == '-' && expect_term && text[term_start..i].trim().is_empty() {
+                sign = -sign;
+                term_start = i + 1;
+            } else if !c.is_whitespace() {
+                expect_term = false;
+            }
+        }
+        Ok(total)
+    }
+
+    fn term(&self, text: &str, depth: usize) -> Result<i64, String> {
+        if text.is_empty() {
+            return Err("Missing value".to_string());
+        }
+        if let Some(number) = number(text) {
+            return Ok(number);
+        }
+        match self.symbols.get(text) {
+            Some(Symbol::Label(addr)) => Ok(*addr as i64),
+            Some(Symbol::Constant(value)) => self.evaluate(value, depth + 1),
+            None => Err(format!("Unknown symbol {}", text)),
+        }
+    }
+}
+
+fn error(file: &str, line: usize, message: String) -> AsmError {
+    AsmError {
+        file: file.to_string(),
+        line,
+        message,
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn split_label(line: &str) -> Option<(&str, &str)> {
+    let (label, rest) = line.split_once(':')?;
+    Some((label.trim(), rest)).filter(|(label, _)| is_identifier(label))
+}
+
+fn split_constant(line: &str) -> Option<(&str, &str)> {
+    let mut words = line.splitn(3, char::is_whitespace);
+    let name = words.next()?;
+    let keyword = words.next()?;
+    let value = words.next()?;
+    Some((name, value.trim())).filter(|_| keyword.eq_ignore_ascii_case("equ"))
+}
+
+// Commas outside of string literals separate operands
+fn split_operands(operands: &str) -> Vec<String> {
+    if operands.is_empty() {
+        return vec![];
+    }
+    let mut split = vec![];
+    let mut current = String::new();
+    let mut in_string = false;
+    for c in operands.chars() {
+        match c {
+            '"' => {
+                in_string = !in_string;
+                current.push(c);
+            }
+            ',' if !in_string => split.push(std::mem::take(&mut current).trim().to_string()),
+            _ => current.push(c),
+        }
+    }
+    split.push(current.trim().to_string());
+    split
+}
+
+fn strip_keyword<'a>(text: &'a str, keyword: &str) -> Option<&'a str> {
+    let (word, rest) = text.split_once(char::is_whitespace)?;
+    Some(rest.trim()).filter(|_| word.eq_ignore_ascii_case(keyword))
+}
+
+fn string_literal(text: &str) -> Option<&str> {
+    text.strip_prefix('"')?.strip_suffix('"')
+}
+
+fn instruction_size(mnemonic: &str, operands: &[String]) -> usize {
+    match operands {
+        [target, source]
+            if mnemonic == "LD"
+                && target.eq_ignore_ascii_case("I")
+                && strip_keyword(source, "long").is_some() =>
+        {
+            4
+        }
+        _ => 2,
+    }
+}
+
+fn data_size(value: &str) -> usize {
+    string_literal(value).map_or(1, str::len)
+}
+
+fn byte(value: i64) -> Result<u8, String> {
+    // Negative bytes are two's complement, like ADD V0, -1
+    if !(-0x80..=0xFF).contains(&value) {
+        return Err(format!("{} doesn't fit in a byte", value));
+    }
+    Ok(value as u8)
+}
+
+fn number(text: &str) -> Option<i64> {
+    let lower = text.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x").or_else(|| lower.strip_prefix('#')) {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+fn register(text: &str) -> Option<usize> {
+    let text = text.trim();
+    let index = text.strip_prefix('V').or_else(|| text.strip_prefix('v'))?;
+    if index.len() != 1 {
+        return None;
+    }
+    usize::from_str_radix(index, 16).ok()
+}
+
+// VX-VY
+fn register_range(text: &str) -> Option<(usize, usize)> {
+    let (first, last) = text.split_once('-')?;
+    Some((register(first)?, register(last)?))
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Names that would read as registers or operands
+fn is_reserved(name: &str) -> bool {
+    register(name).is_some()
+        || ["I", "DT", "ST", "K", "F", "HF", "B", "R", "LONG"]
+            .iter()
+            .any(|reserved| name.eq_ignore_ascii_case(reserved))
+}
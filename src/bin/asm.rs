@@ -0,0 +1,79 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use rust_chip8::asm;
+
+const EXIT_USAGE: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+
+struct Options {
+    source_path: String,
+    output_path: Option<String>,
+}
+
+fn usage() -> String {
+    "Usage: asm <source> [-o output.ch8]".to_string()
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut source_path = None;
+    let mut output_path = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value", arg));
+        match arg.as_str() {
+            "-o" | "--output" => output_path = Some(value()?),
+            flag if flag.starts_with('-') => return Err(usage()),
+            _ => source_path = Some(arg),
+        }
+    }
+
+    Ok(Options {
+        source_path: source_path.ok_or_else(usage)?,
+        output_path,
+    })
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|message| {
+        eprintln!("{}", message);
+        process::exit(EXIT_USAGE);
+    });
+
+    let assembly = asm::assemble_file(&options.source_path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(EXIT_ERROR);
+    });
+
+    // The symbol and source maps go next to the ROM
+    let output = match &options.output_path {
+        Some(path) => Path::new(path).to_path_buf(),
+        None => Path::new(&options.source_path).with_extension("ch8"),
+    };
+    let outputs = [
+        (output.clone(), assembly.binary.clone()),
+        (
+            output.with_extension("sym"),
+            assembly.symbol_map().into_bytes(),
+        ),
+        (
+            output.with_extension("map"),
+            assembly.source_map.to_text().into_bytes(),
+        ),
+    ];
+    for (path, data) in &outputs {
+        if let Err(e) = fs::write(path, data) {
+            eprintln!("Unable to write {}: {}", path.display(), e);
+            process::exit(EXIT_ERROR);
+        }
+    }
+    println!(
+        "{}: {} bytes, {} symbols",
+        output.display(),
+        assembly.binary.len(),
+        assembly.symbols.len()
+    );
+}
@@ -0,0 +1,60 @@
+use std::env;
+use std::fs;
+use std::process;
+
+use rust_chip8::disasm;
+
+const EXIT_USAGE: i32 = 1;
+
+struct Options {
+    rom_path: String,
+    base: usize,
+    linear: bool,
+}
+
+fn usage() -> String {
+    "Usage: disasm <rom> [--base ADDR] [--linear]".to_string()
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut rom_path = None;
+    let mut base = 0x200;
+    let mut linear = false;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value", arg));
+        match arg.as_str() {
+            "--base" => {
+                base = usize::from_str_radix(value()?.trim_start_matches("0x"), 16)
+                    .map_err(|_| usage())?
+            }
+            "--linear" => linear = true,
+            flag if flag.starts_with("--") => return Err(usage()),
+            _ => rom_path = Some(arg),
+        }
+    }
+
+    Ok(Options {
+        rom_path: rom_path.ok_or_else(usage)?,
+        base,
+        linear,
+    })
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|message| {
+        eprintln!("{}", message);
+        process::exit(EXIT_USAGE);
+    });
+
+    let rom = fs::read(&options.rom_path).unwrap_or_else(|e| {
+        eprintln!("Unable to read {}: {}", options.rom_path, e);
+        process::exit(EXIT_USAGE);
+    });
+    if options.linear {
+        print!("{}", disasm::linear_listing(&rom, options.base));
+    } else {
+        print!("{}", disasm::listing(&rom, options.base));
+    }
+}
@@ -0,0 +1,310 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::process;
+
+extern crate env_logger;
+use rust_chip8::boot::{boot, BootOptions};
+use rust_chip8::chip8::{KeyWait, MemoryPolicy};
+use rust_chip8::database;
+use rust_chip8::movie::{Movie, MovieMode};
+use rust_chip8::random::RngAlgorithm;
+use rust_chip8::timing::Timing;
+use rust_chip8::{Chip8, Chip8Exception, State};
+
+const EXIT_FAULT: i32 = 2;
+const EXIT_USAGE: i32 = 1;
+
+enum Dump {
+    Ascii,
+    Png(String),
+}
+
+struct Options {
+    rom_path: String,
+    cycles: Option<usize>,
+    frames: Option<usize>,
+    boot: BootOptions,
+    input_path: Option<String>,
+    record_path: Option<String>,
+    play_path: Option<String>,
+    dump: Dump,
+}
+
+fn usage() -> String {
+    "Usage: headless <rom> [--cycles N | --frames N] [--ipf N] [--vip-timing] \
+     [--input script | --play movie] [--record movie] [--png output.png] [--platform name] [--wrap-memory] [--key-press-only] [--seed N] [--vip-rng]"
+        .to_string()
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut rom_path = None;
+    let mut cycles = None;
+    let mut frames = None;
+    let mut boot = BootOptions::default();
+    let mut input_path = None;
+    let mut record_path = None;
+    let mut play_path = None;
+    let mut dump = Dump::Ascii;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value", arg));
+        match arg.as_str() {
+            "--cycles" => cycles = Some(value()?.parse().map_err(|_| usage())?),
+            "--frames" => frames = Some(value()?.parse().map_err(|_| usage())?),
+            "--ipf" => {
+                boot.instructions_per_frame = Some(
+                    value()?
+                        .parse()
+                        .ok()
+                        .filter(|ipf| *ipf > 0)
+                        .ok_or_else(usage)?,
+                )
+            }
+            "--vip-timing" => boot.timing = Timing::CosmacVip,
+            "--input" => input_path = Some(value()?),
+            "--record" => record_path = Some(value()?),
+            "--play" => play_path = Some(value()?),
+            "--png" => dump = Dump::Png(value()?),
+            "--platform" => boot.platform = Some(value()?.parse()?),
+            "--wrap-memory" => boot.memory_policy = MemoryPolicy::Wrap,
+            "--seed" => boot.rng_seed = Some(value()?.parse().map_err(|_| usage())?),
+            "--vip-rng" => boot.rng_algorithm = RngAlgorithm::CosmacVip,
+            "--key-press-only" => boot.key_press_only = true,
+            flag if flag.starts_with("--") => return Err(usage()),
+            _ => rom_path = Some(arg),
+        }
+    }
+
+    if record_path.is_some() && play_path.is_some() {
+        return Err("--record and --play can't be combined".to_string());
+    }
+    if input_path.is_some() && play_path.is_some() {
+        return Err("--input and --play can't be combined".to_string());
+    }
+
+    Ok(Options {
+        rom_path: rom_path.ok_or_else(usage)?,
+        cycles,
+        frames,
+        boot,
+        input_path,
+        record_path,
+        play_path,
+        dump,
+    })
+}
+
+/// Input scripts hold one `<frame> <keys>` line per change of the keypad, `keys` being the
+/// hexadecimal keys held from that frame on, or `-` for none. `#` starts a comment.
+fn parse_input_script(script: &str) -> Result<Vec<(usize, [bool; 16])>, String> {
+    let mut events = vec![];
+    for (number, line) in script.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let error = || format!("Invalid input script line {}: {}", number + 1, line);
+        let mut fields = line.split_whitespace();
+        let frame = fields
+            .next()
+            .and_then(|frame| frame.parse().ok())
+            .ok_or_else(error)?;
+        let mut keypad = [false; 16];
+        match fields.next().ok_or_else(error)? {
+            "-" => {}
+            keys => {
+                for key in keys.chars() {
+                    keypad[key.to_digit(16).ok_or_else(error)? as usize] = true;
+                }
+            }
+        }
+        events.push((frame, keypad));
+    }
+    events.sort_by_key(|(frame, _)| *frame);
+    Ok(events)
+}
+
+/// Run whole frames for as long as `cycles` allows, then the leftover cycles of a partial frame.
+/// The keypad of whole frames goes through `movie`.
+fn run(
+    machine: &mut Chip8,
+    cycles: usize,
+    input: &[(usize, [bool; 16])],
+    movie: &mut MovieMode,
+) -> Result<(), Chip8Exception> {
+    let mut keypad = [false; 16];
+    let mut pending_input = input.iter().peekable();
+    let frames = cycles / machine.instructions_per_frame;
+
+    for frame in 0..=frames {
+        while let Some((_, keys)) = pending_input.next_if(|(at, _)| *at <= frame) {
+            keypad = *keys;
+        }
+
+        if frame == frames {
+            for _ in 0..cycles % machine.instructions_per_frame {
+                machine.tick_clock(&keypad)?;
+            }
+        } else {
+            machine.run_frame(&movie.next_frame(keypad))?;
+        }
+        if machine.state == State::Halted {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn print_registers(machine: &Chip8) {
+    println!(
+        "PC: 0x{:04x}, I: 0x{:04x}, SP: 0x{:02x}, DT: 0x{:02x}, ST: 0x{:02x}",
+        machine.pc, machine.i, machine.sp, machine.dt, machine.st
+    );
+    println!("V:  {:02x?}", machine.v);
+    println!("Stack: {:04x?}", &machine.stack[..machine.sp]);
+    match machine.key_wait {
+        KeyWait::Press(vx) => println!("Waiting for a key press into V{:x}", vx),
+        KeyWait::Release(vx, key) => {
+            println!("Waiting for key {:X} to be released into V{:x}", key, vx)
+        }
+        KeyWait::None => {}
+    }
+    println!(
+        "Memory SHA-1: {}",
+        database::hash(&machine.memory[..machine.address_space()])
+    );
+}
+
+fn print_framebuffer(machine: &Chip8) {
+    for y in 0..machine.height() {
+        let row: String = (0..machine.width())
+            .map(|x| match machine.get_pixel(x, y) {
+                0 => '.',
+                1 => '#',
+                planes => std::char::from_digit(planes as u32, 16).unwrap(),
+            })
+            .collect();
+        println!("{}", row);
+    }
+}
+
+fn write_png(machine: &Chip8, path: &str) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| e.to_string())?;
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        machine.width() as u32,
+        machine.height() as u32,
+    );
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    // Plane 1 alone is white, other bitplane combinations get darker greys
+    let pixels: Vec<u8> = (0..machine.height())
+        .flat_map(|y| (0..machine.width()).map(move |x| (x, y)))
+        .map(|(x, y)| match machine.get_pixel(x, y) {
+            0 => 0,
+            planes => 255 - (planes - 1) * 16,
+        })
+        .collect();
+
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&pixels))
+        .map_err(|e| e.to_string())
+}
+
+fn main() {
+    env_logger::init();
+    let mut options = parse_args().unwrap_or_else(|message| {
+        eprintln!("{}", message);
+        process::exit(EXIT_USAGE);
+    });
+
+    let read = |path: &str| {
+        fs::read(path).unwrap_or_else(|e| {
+            eprintln!("Unable to read {}: {}", path, e);
+            process::exit(EXIT_USAGE);
+        })
+    };
+
+    let rom = read(&options.rom_path);
+    let input = match &options.input_path {
+        Some(path) => {
+            parse_input_script(&String::from_utf8_lossy(&read(path))).unwrap_or_else(|message| {
+                eprintln!("{}", message);
+                process::exit(EXIT_USAGE);
+            })
+        }
+        None => vec![],
+    };
+
+    let playback = options.play_path.as_ref().map(|path| {
+        Movie::load(path, &rom).unwrap_or_else(|message| {
+            eprintln!("{}", message);
+            process::exit(EXIT_USAGE);
+        })
+    });
+    let recording_seed = match options.record_path {
+        Some(_) => Some(options.boot.recording_seed()),
+        None => None,
+    };
+
+    let rom_info = database::lookup(&rom);
+    let mut machine = boot(&options.boot, rom_info.as_ref(), &rom, playback.as_ref())
+        .unwrap_or_else(|exception| {
+            eprintln!("Unable to load ROM: {}", exception);
+            process::exit(EXIT_USAGE);
+        });
+
+    // Frames are converted to cycles once the instructions per frame are known, movies play
+    // to the end by default
+    let frames = options.frames.unwrap_or(match &playback {
+        Some(movie) => movie.frames.len(),
+        None => 600,
+    });
+    let cycles = options
+        .cycles
+        .unwrap_or(frames * machine.instructions_per_frame);
+    // Movies only hold whole frames, a partial one would run but never replay
+    if recording_seed.is_some() && !cycles.is_multiple_of(machine.instructions_per_frame) {
+        eprintln!(
+            "--record needs whole frames, --cycles must be a multiple of {}",
+            machine.instructions_per_frame
+        );
+        process::exit(EXIT_USAGE);
+    }
+
+    let mut movie = match (playback, recording_seed) {
+        (Some(movie), _) => MovieMode::Playing { movie, frame: 0 },
+        (None, Some(seed)) => MovieMode::Recording(Movie::new(&machine, &rom, seed)),
+        _ => MovieMode::Off,
+    };
+
+    let result = run(&mut machine, cycles, &input, &mut movie);
+
+    if let (Some(path), MovieMode::Recording(movie)) = (&options.record_path, &movie) {
+        if let Err(e) = fs::write(path, movie.to_bytes()) {
+            eprintln!("Unable to write {}: {}", path, e);
+            process::exit(EXIT_USAGE);
+        }
+    }
+
+    print_registers(&machine);
+    match &options.dump {
+        Dump::Ascii => print_framebuffer(&machine),
+        Dump::Png(path) => {
+            if let Err(message) = write_png(&machine, path) {
+                eprintln!("Unable to write {}: {}", path, message);
+                process::exit(EXIT_USAGE);
+            }
+        }
+    }
+
+    if let Err(exception) = result {
+        eprintln!("CPU halted: {}", exception);
+        process::exit(EXIT_FAULT);
+    }
+}
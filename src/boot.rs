@@ -0,0 +1,84 @@
+use crate::chip8::{Chip8, Chip8Exception, MemoryPolicy};
+use crate::database::RomInfo;
+use crate::movie::Movie;
+use crate::quirks::Platform;
+use crate::random::{self, RngAlgorithm};
+use crate::timing::Timing;
+
+/// Machine settings shared by the command lines of the frontends
+#[derive(Clone)]
+pub struct BootOptions {
+    pub platform: Option<Platform>,
+    pub memory_policy: MemoryPolicy,
+    pub timing: Timing,
+    pub instructions_per_frame: Option<usize>,
+    pub key_press_only: bool,
+    pub rng_seed: Option<u32>,
+    pub rng_algorithm: RngAlgorithm,
+}
+
+impl BootOptions {
+    /// Recordings need a known seed, one is picked from the clock unless given
+    pub fn recording_seed(&mut self) -> u32 {
+        *self.rng_seed.get_or_insert_with(random::time_seed)
+    }
+}
+
+impl Default for BootOptions {
+    fn default() -> Self {
+        Self {
+            platform: None,
+            memory_policy: MemoryPolicy::Strict,
+            timing: Timing::InstructionsPerFrame,
+            instructions_per_frame: None,
+            key_press_only: false,
+            rng_seed: None,
+            rng_algorithm: RngAlgorithm::Xorshift,
+        }
+    }
+}
+
+/// A fresh machine running `rom` from 0x200, set up by `options` and the ROM database, or like
+/// the recorded one when replaying `movie`
+pub fn boot(
+    options: &BootOptions,
+    rom_info: Option<&RomInfo>,
+    rom: &[u8],
+    movie: Option<&Movie>,
+) -> Result<Chip8, Chip8Exception> {
+    let mut machine = match options.rng_seed {
+        Some(seed) => Chip8::with_seed(seed),
+        None => Chip8::new(),
+    };
+    machine.rng.algorithm = options.rng_algorithm;
+    machine.memory_policy = options.memory_policy;
+    machine.timing = options.timing;
+    // The command line wins over the ROM database
+    if let Some(platform) = options.platform {
+        machine.set_platform(platform);
+    } else if let Some(info) = rom_info {
+        if let Some(platform) = info.platform {
+            machine.set_platform(platform);
+        }
+        if let Some(quirks) = info.quirks {
+            machine.quirks = quirks;
+        }
+    }
+    if options.key_press_only {
+        machine.quirks.key_press_only = true;
+    }
+    let tickrate = rom_info
+        .and_then(|info| info.tickrate)
+        .map(|tickrate| tickrate as usize);
+    if let Some(ipf) = options.instructions_per_frame.or(tickrate) {
+        machine.instructions_per_frame = ipf;
+    }
+    // A movie replays with the settings it was recorded with
+    if let Some(movie) = movie {
+        movie.configure(&mut machine);
+    }
+    machine.init();
+    machine.load_rom(0x200, rom)?;
+    machine.set_pc(0x200);
+    Ok(machine)
+}
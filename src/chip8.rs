@@ -1,8 +1,12 @@
-use rand::Rng;
+use std::fmt;
 use std::time::Instant;
 extern crate log;
 use log::{debug, info};
 
+use crate::quirks::{MemoryIncrement, Platform, Quirks};
+use crate::random::{Rng, RngAlgorithm};
+use crate::timing::{self, Timing};
+
 static FONT: [u8; 5 * 16] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
     0x20, 0x60, 0x20, 0x20, 0x70, // 1
@@ -22,15 +26,71 @@ static FONT: [u8; 5 * 16] = [
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
 
+// SuperChip-8 10-byte large digits
+static BIG_FONT: [u8; 10 * 16] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+pub const LORES_WIDTH: usize = 64;
+pub const LORES_HEIGHT: usize = 32;
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+
+pub const MEMORY_SIZE: usize = 0x1000;
+pub const XO_CHIP_MEMORY_SIZE: usize = 0x10000;
+pub const MAX_PLANES: usize = 4;
+
+/// What to do when an instruction addresses memory past the end of the address space.
+#[derive(Copy, Clone, PartialEq)]
+pub enum MemoryPolicy {
+    /// Raise `MemoryOverflow`, halting the CPU.
+    Strict,
+    /// Wrap the address around the address space, like the original 12-bit address bus.
+    Wrap,
+}
+
 #[derive(Copy, Clone, PartialEq)]
 pub enum State {
     Paused,
     Running,
+    Halted,
+}
+
+/// Progress of an FX0A instruction, the CPU is stalled while it waits
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum KeyWait {
+    None,
+    /// Waiting for a key to go down, keys already held when FX0A ran don't count. Holds X.
+    Press(usize),
+    /// A key went down, waiting for it to be released before storing it in VX. Holds X and the key.
+    Release(usize, u8),
 }
 
 pub struct Chip8 {
-    pub memory: [u8; 4096],
-    pub video_memory: [bool; 64 * 32],
+    pub memory: [u8; XO_CHIP_MEMORY_SIZE],
+    // One bit per bitplane
+    pub video_memory: [u8; HIRES_WIDTH * HIRES_HEIGHT],
+    pub hires: bool,
+    pub xo_chip: bool,
+    pub planes: u8,
+    pub audio_pattern: Option<[u8; 16]>,
+    pub pitch: u8,
+    pub rpl: [u8; 16],
     pub stack: [usize; 16],
     pub v: [u8; 16],
     pub keys: [bool; 16],
@@ -39,16 +99,23 @@ pub struct Chip8 {
     pub st: u8,
     pub pc: usize,
     pub sp: usize,
-    pub timer_counter: usize,
     pub font_base_addr: usize,
-    pub waiting_for_key: bool,
-    pub read_key_registry: usize,
+    pub big_font_base_addr: usize,
+    pub key_wait: KeyWait,
+    // A DXYN is stalled until the next frame, `vblank` is set once that frame starts
+    pub waiting_for_vblank: bool,
+    pub vblank: bool,
     pub state: State,
+    pub instructions_per_frame: usize,
+    pub timing: Timing,
+    // Machine cycles left in the current frame under `Timing::CosmacVip`, overshoots carry over
+    pub cycle_budget: i32,
+    pub memory_policy: MemoryPolicy,
     pub video_memory_tainted: bool,
     pub chrono: std::time::Instant,
 
-    pub use_original_shr_shl: bool,
-    pub ignore_short_beeps: bool,
+    pub quirks: Quirks,
+    pub rng: Rng,
 }
 
 #[derive(Debug)]
@@ -59,6 +126,28 @@ pub enum Chip8Exception {
     MemoryUnderflow,
     InvalidInstruction,
     UHM,
+    Fault {
+        pc: usize,
+        op_code: u16,
+        exception: Box<Chip8Exception>,
+    },
+}
+
+impl fmt::Display for Chip8Exception {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Chip8Exception::Fault {
+                pc,
+                op_code,
+                exception,
+            } => write!(
+                f,
+                "{} at PC 0x{:04x} (opcode 0x{:04x})",
+                exception, pc, op_code
+            ),
+            exception => write!(f, "{:?}", exception),
+        }
+    }
 }
 
 impl Chip8 {
@@ -66,80 +155,303 @@ impl Chip8 {
         Self::default()
     }
 
-    pub fn get_pixel(&self, x: usize, y: usize) -> bool {
-        self.video_memory[64 * y + x]
+    /// A machine whose CXNN numbers are the same on every run
+    pub fn with_seed(seed: u32) -> Self {
+        Self {
+            rng: Rng::new(seed, RngAlgorithm::Xorshift),
+            ..Self::default()
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        if self.hires {
+            HIRES_WIDTH
+        } else {
+            LORES_WIDTH
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        if self.hires {
+            HIRES_HEIGHT
+        } else {
+            LORES_HEIGHT
+        }
+    }
+
+    pub fn set_platform(&mut self, platform: Platform) {
+        self.quirks = platform.quirks();
+        self.xo_chip = platform == Platform::XoChip;
+    }
+
+    pub fn address_space(&self) -> usize {
+        if self.xo_chip {
+            XO_CHIP_MEMORY_SIZE
+        } else {
+            MEMORY_SIZE
+        }
+    }
+
+    // XO-CHIP plays the 128 bit audio pattern at 4000 * 2 ^ ((pitch - 64) / 48) bits per second
+    pub fn audio_sample_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> u8 {
+        self.video_memory[self.width() * y + x]
     }
 
     pub fn init(&mut self) {
-        self.memory[self.font_base_addr..self.font_base_addr + 80].copy_from_slice(&FONT);
+        self.memory[self.font_base_addr..self.font_base_addr + FONT.len()].copy_from_slice(&FONT);
+        self.memory[self.big_font_base_addr..self.big_font_base_addr + BIG_FONT.len()]
+            .copy_from_slice(&BIG_FONT);
         for i in self.video_memory.iter_mut() {
-            *i = false;
+            *i = 0;
         }
+        self.video_memory_tainted = true;
     }
 
-    pub fn load_rom(&mut self, addr: usize, program: &[u8]) {
-        self.memory[addr..].copy_from_slice(program);
+    pub fn load_rom(&mut self, addr: usize, program: &[u8]) -> Result<(), Chip8Exception> {
+        if addr + program.len() > self.address_space() {
+            return Err(Chip8Exception::MemoryOverflow);
+        }
+        self.memory[addr..addr + program.len()].copy_from_slice(program);
+        Ok(())
     }
 
-    pub fn tick_clock(&mut self, input: &[bool; 16]) {
-        if self.state == State::Paused {
-            return;
+    pub fn resolve_address(&self, addr: usize) -> Result<usize, Chip8Exception> {
+        if addr < self.address_space() {
+            return Ok(addr);
+        }
+
+        match self.memory_policy {
+            MemoryPolicy::Strict => Err(Chip8Exception::MemoryOverflow),
+            MemoryPolicy::Wrap => Ok(addr % self.address_space()),
         }
+    }
+
+    pub fn read_memory(&self, addr: usize) -> Result<u8, Chip8Exception> {
+        Ok(self.memory[self.resolve_address(addr)?])
+    }
+
+    pub fn write_memory(&mut self, addr: usize, value: u8) -> Result<(), Chip8Exception> {
+        let addr = self.resolve_address(addr)?;
+        self.memory[addr] = value;
+        Ok(())
+    }
 
-        self.next(input);
+    fn memory_window(&self, addr: usize, len: usize) -> &[u8] {
+        let start = addr.min(self.address_space());
+        let end = (addr + len).min(self.address_space());
+        &self.memory[start..end]
     }
 
-    pub fn next(&mut self, input: &[bool; 16]) {
+    pub fn tick_clock(&mut self, input: &[bool; 16]) -> Result<(), Chip8Exception> {
+        if self.state != State::Running {
+            return Ok(());
+        }
+
+        self.next(input)
+    }
+
+    pub fn next(&mut self, input: &[bool; 16]) -> Result<(), Chip8Exception> {
+        if self.state == State::Halted {
+            return Ok(());
+        }
+
         self.read_input(input);
 
-        if self.waiting_for_key {
+        if self.waiting_for_key() {
             info!("Waiting for keypress");
-            return;
+            return Ok(());
+        }
+
+        if self.waiting_for_vblank {
+            // Timers don't run while paused, every single step counts as a frame
+            if self.state != State::Paused {
+                debug!("Waiting for vblank");
+                return Ok(());
+            }
+            self.end_vblank_wait();
         }
 
         debug!("{}", self);
 
-        self.cycle();
+        self.cycle()
+    }
+
+    /// Execute one instruction for a debugger, which holds the machine without pausing it. Like
+    /// a paused step this counts as a frame, so a DXYN stalled for vblank draws right away.
+    pub fn step(&mut self, input: &[bool; 16]) -> Result<(), Chip8Exception> {
+        if self.waiting_for_vblank {
+            self.end_vblank_wait();
+        }
+        self.next(input)?;
+        if self.waiting_for_vblank {
+            self.end_vblank_wait();
+            self.next(input)?;
+        }
+        Ok(())
     }
 
     pub fn int(&mut self) {
         self.state = match self.state {
             State::Paused => State::Running,
             State::Running => State::Paused,
+            State::Halted => State::Halted,
         }
     }
 
+    pub fn waiting_for_key(&self) -> bool {
+        self.key_wait != KeyWait::None
+    }
+
     pub fn read_input(&mut self, input: &[bool]) {
         for (i, item) in input.iter().enumerate().take(16) {
+            let pressed = *item && !self.keys[i];
             self.keys[i] = *item;
-            if *item && self.waiting_for_key {
-                self.v[self.read_key_registry] = i as u8;
-                self.waiting_for_key = false;
-            }
+            self.key_wait = match self.key_wait {
+                KeyWait::Press(vx) if self.quirks.key_press_only && *item => {
+                    self.v[vx] = i as u8;
+                    KeyWait::None
+                }
+                KeyWait::Press(vx) if pressed => KeyWait::Release(vx, i as u8),
+                KeyWait::Release(vx, key) if key as usize == i && !*item => {
+                    self.v[vx] = key;
+                    KeyWait::None
+                }
+                key_wait => key_wait,
+            };
         }
     }
 
-    pub fn cycle(&mut self) {
+    pub fn cycle(&mut self) -> Result<(), Chip8Exception> {
         let _now = Instant::now();
         self.video_memory_tainted = false;
-        let op_code = self.fetch_instruction().unwrap();
-        self.decode_and_exec_instruction(op_code).unwrap();
-        self.update_timers()
+        let pc = self.pc;
+        let result = self
+            .fetch_instruction()
+            .map_err(|e| (0, e))
+            .and_then(|op_code| {
+                self.decode_and_exec_instruction(op_code)
+                    .map_err(|e| (op_code, e))
+            });
+
+        if let Err((op_code, exception)) = result {
+            self.state = State::Halted;
+            return Err(Chip8Exception::Fault {
+                pc,
+                op_code,
+                exception: Box::new(exception),
+            });
+        }
+
+        Ok(())
     }
 
-    pub fn update_timers(&mut self) {
-        if self.dt == 0 && self.st == 0 {
-            self.timer_counter = 0;
-            return;
+    /// Run one 60 Hz frame: as many instructions as `timing` allows followed by a timer tick
+    pub fn run_frame(&mut self, input: &[bool; 16]) -> Result<(), Chip8Exception> {
+        self.run_frame_until(input, |_| false).map(|_| ())
+    }
+
+    /// `run_frame`, asking `stop` before every instruction. If it answers true the frame ends
+    /// right there without ticking the timers, and `Ok(true)` is returned.
+    pub fn run_frame_until<F>(
+        &mut self,
+        input: &[bool; 16],
+        mut stop: F,
+    ) -> Result<bool, Chip8Exception>
+    where
+        F: FnMut(&Chip8) -> bool,
+    {
+        if self.state != State::Running {
+            return Ok(false);
+        }
+
+        if self.timing == Timing::CosmacVip {
+            self.cycle_budget += timing::VIP_CYCLES_PER_FRAME;
+        }
+
+        // Every cycle resets the taint, the frame is tainted if any of its instructions drew
+        let mut tainted = false;
+        let mut executed = 0;
+        loop {
+            let frame_over = match self.timing {
+                Timing::InstructionsPerFrame => executed >= self.instructions_per_frame,
+                Timing::CosmacVip => self.cycle_budget <= 0,
+            };
+            if frame_over {
+                break;
+            }
+
+            if stop(self) {
+                self.video_memory_tainted = tainted;
+                return Ok(true);
+            }
+
+            match self.timing {
+                Timing::InstructionsPerFrame => executed += 1,
+                Timing::CosmacVip => {
+                    // FX0A spins for the rest of the frame
+                    self.cycle_budget -= match self.peek_instruction() {
+                        Some(op_code) if !self.waiting_for_key() && !self.waiting_for_vblank => {
+                            timing::vip_machine_cycles(self, op_code)
+                        }
+                        _ => self.cycle_budget,
+                    }
+                }
+            }
+
+            let result = self.tick_clock(input);
+            tainted |= self.video_memory_tainted;
+            if result.is_err() || self.state != State::Running {
+                self.video_memory_tainted = tainted;
+                return result.map(|_| false);
+            }
+
+            if self.waiting_for_vblank {
+                // The CPU idles until the interrupt, the rest of the frame is lost
+                self.cycle_budget = 0;
+                break;
+            }
         }
+        self.video_memory_tainted = tainted;
 
-        self.timer_counter = (self.timer_counter + 1) % 10;
-        if self.timer_counter != 0 {
+        self.tick_timers();
+        Ok(false)
+    }
+
+    /// Count down the delay and sound timers, the host must call it at 60 Hz
+    pub fn tick_timers(&mut self) {
+        if self.state != State::Running {
             return;
         }
 
         self.dt = self.dt.saturating_sub(1);
         self.st = self.st.saturating_sub(1);
+        self.rng.tick();
+
+        if self.waiting_for_vblank {
+            self.end_vblank_wait();
+        }
+    }
+
+    fn end_vblank_wait(&mut self) {
+        self.waiting_for_vblank = false;
+        self.vblank = true;
+    }
+
+    /// With the display wait quirk, stall the DXYN at PC until the next frame and tell if it did
+    fn wait_for_vblank(&mut self) -> bool {
+        // SUPER-CHIP only waits in lores
+        if !self.quirks.display_wait || self.hires || self.vblank {
+            self.vblank = false;
+            return false;
+        }
+
+        self.waiting_for_vblank = true;
+        self.pc -= 2;
+        true
     }
 
     pub fn set_pc(&mut self, pc: usize) {
@@ -157,15 +469,25 @@ impl Chip8 {
         let nnn = (op_code & 0x0FFF) as usize;
         let kk = (op_code & 0x00FF) as u8;
 
+        // XO-CHIP instructions only exist in XO-CHIP mode, the one skips know F000 NNNN is 4 bytes in
         match nibbles {
             [0x0, 0x0, 0xE, 0x0] => self.cls(),
             [0x0, 0x0, 0xE, 0xE] => self.ret(),
+            [0x0, 0x0, 0xC, n] => self.scd(n as u8),
+            [0x0, 0x0, 0xD, n] => self.scu(n as u8),
+            [0x0, 0x0, 0xF, 0xB] => self.scr(),
+            [0x0, 0x0, 0xF, 0xC] => self.scl(),
+            [0x0, 0x0, 0xF, 0xD] => self.exit(),
+            [0x0, 0x0, 0xF, 0xE] => self.low(),
+            [0x0, 0x0, 0xF, 0xF] => self.high(),
             [0x0, _, _, _] => self.sys_addr(nnn),
             [0x1, _, _, _] => self.jp_addr(nnn),
             [0x2, _, _, _] => self.call_addr(nnn),
             [0x3, vx, _, _] => self.se_vx_byte(vx, kk),
             [0x4, vx, _, _] => self.sne_vx_byte(vx, kk),
             [0x5, vx, vy, 0x0] => self.se_vx_vy(vx, vy),
+            [0x5, vx, vy, 0x2] if self.xo_chip => self.ld_mem_i_vx_vy(vx, vy),
+            [0x5, vx, vy, 0x3] if self.xo_chip => self.ld_vx_vy_mem_i(vx, vy),
             [0x6, vx, _, _] => self.ld_vx_byte(vx, kk),
             [0x7, vx, _, _] => self.add_vx_byte(vx, kk),
             [0x8, vx, vy, 0x0] => self.ld_vx_vy(vx, vy),
@@ -181,18 +503,26 @@ impl Chip8 {
             [0xA, _, _, _] => self.ld_i_addr(nnn),
             [0xB, _, _, _] => self.jp_v0_addr(nnn),
             [0xC, vx, _, _] => self.rnd_vx_byte(vx, kk),
+            [0xD, vx, vy, 0x0] => self.drw_vx_vy_0(vx, vy),
             [0xD, vx, vy, n] => self.drw_vx_vy_nibble(vx, vy, n as u8),
             [0xE, vx, 0x9, 0xE] => self.skp_vx(vx),
             [0xE, vx, 0xA, 0x1] => self.sknp_vx(vx),
+            [0xF, 0x0, 0x0, 0x0] if self.xo_chip => self.ld_i_long(),
+            [0xF, n, 0x0, 0x1] if self.xo_chip => self.plane(n as u8),
+            [0xF, 0x0, 0x0, 0x2] if self.xo_chip => self.audio(),
             [0xF, vx, 0x0, 0x7] => self.ld_vx_dt(vx),
             [0xF, vx, 0x0, 0xA] => self.ld_vx_k(vx),
             [0xF, vx, 0x1, 0x5] => self.ld_dt_vx(vx),
             [0xF, vx, 0x1, 0x8] => self.ld_st_vx(vx),
             [0xF, vx, 0x1, 0xE] => self.add_i_vx(vx),
             [0xF, vx, 0x2, 0x9] => self.ld_f_vx(vx),
+            [0xF, vx, 0x3, 0x0] => self.ld_hf_vx(vx),
             [0xF, vx, 0x3, 0x3] => self.ld_b_vx(vx),
+            [0xF, vx, 0x3, 0xA] if self.xo_chip => self.pitch_vx(vx),
             [0xF, vx, 0x5, 0x5] => self.ld_mem_i_vx(vx),
             [0xF, vx, 0x6, 0x5] => self.ld_vx_mem_i(vx),
+            [0xF, vx, 0x7, 0x5] => self.ld_r_vx(vx),
+            [0xF, vx, 0x8, 0x5] => self.ld_vx_r(vx),
             _ => Err(Chip8Exception::InvalidInstruction),
         }
     }
@@ -218,40 +548,55 @@ impl Chip8 {
     }
 
     pub fn fetch_instruction(&mut self) -> Result<u16, Chip8Exception> {
-        if self.pc + 2 >= 4096 {
-            return Err(Chip8Exception::MemoryOverflow);
-        }
-
-        let op_code = (self.memory[self.pc] as u16) << 8 | self.memory[self.pc + 1] as u16;
+        let pc = self.resolve_address(self.pc)?;
+        let (high, low) = (self.memory[pc], self.read_memory(pc + 1)?);
+        let op_code = (high as u16) << 8 | low as u16;
         debug!(
             "=PC:0x{:04x} -> 0x{:04x} ({:x}|{:x})",
-            self.pc,
-            op_code,
-            self.memory[self.pc],
-            self.memory[self.pc + 1]
+            pc, op_code, high, low
         );
-        self.pc += 2;
+        self.pc = pc + 2;
         Ok(op_code)
     }
 
+    /// The instruction at PC, without fetching it
+    pub fn peek_instruction(&self) -> Option<u16> {
+        let high = self.read_memory(self.pc).ok()?;
+        let low = self.read_memory(self.pc + 1).ok()?;
+        Some((high as u16) << 8 | low as u16)
+    }
+
+    fn skip_next_instruction(&mut self) -> Result<(), Chip8Exception> {
+        // XO-CHIP's F000 NNNN is the only instruction that takes 4 bytes
+        if self.xo_chip
+            && self.read_memory(self.pc)? == 0xF0
+            && self.read_memory(self.pc + 1)? == 0x00
+        {
+            self.pc += 2;
+        }
+        self.pc += 2;
+        Ok(())
+    }
+
     pub fn cls(&mut self) -> Result<(), Chip8Exception> {
         debug!("CLS");
         for i in self.video_memory.iter_mut() {
-            *i = false
+            *i &= !self.planes
         }
         self.video_memory_tainted = true;
         Ok(())
     }
 
+    // Pixels keep the row stride of the old resolution, changing it clears every plane
+    fn clear_all_planes(&mut self) {
+        self.video_memory.fill(0);
+        self.video_memory_tainted = true;
+    }
+
     pub fn ret(&mut self) -> Result<(), Chip8Exception> {
         debug!("RET");
-        match self.stack_pop() {
-            Ok(value) => {
-                self.pc = value;
-                Ok(())
-            }
-            Err(e) => Err(e),
-        }
+        self.pc = self.stack_pop()?;
+        Ok(())
     }
 
     pub fn sys_addr(&mut self, addr: usize) -> Result<(), Chip8Exception> {
@@ -267,7 +612,7 @@ impl Chip8 {
 
     pub fn call_addr(&mut self, addr: usize) -> Result<(), Chip8Exception> {
         debug!("CALL addr({:x})", addr);
-        self.stack_push(self.pc).unwrap();
+        self.stack_push(self.pc)?;
         self.pc = addr;
         Ok(())
     }
@@ -275,7 +620,7 @@ impl Chip8 {
     pub fn se_vx_byte(&mut self, vx: usize, byte: u8) -> Result<(), Chip8Exception> {
         debug!("SE V{:x}({:x}), byte({:x})", vx, self.v[vx], byte);
         if self.v[vx] == byte {
-            self.pc += 2
+            self.skip_next_instruction()?;
         }
         Ok(())
     }
@@ -283,7 +628,7 @@ impl Chip8 {
     pub fn sne_vx_byte(&mut self, vx: usize, byte: u8) -> Result<(), Chip8Exception> {
         debug!("SNE V{:x}, byte({:x})", vx, byte);
         if self.v[vx] != byte {
-            self.pc += 2
+            self.skip_next_instruction()?;
         }
         Ok(())
     }
@@ -291,7 +636,7 @@ impl Chip8 {
     pub fn se_vx_vy(&mut self, vx: usize, vy: usize) -> Result<(), Chip8Exception> {
         debug!("SE V{:x}, V{:x}", vx, vy);
         if self.v[vx] == self.v[vy] {
-            self.pc += 2
+            self.skip_next_instruction()?;
         }
         Ok(())
     }
@@ -318,21 +663,30 @@ impl Chip8 {
     pub fn or_vx_vy(&mut self, vx: usize, vy: usize) -> Result<(), Chip8Exception> {
         debug!("OR V{:x}, V{:x}", vx, vy);
         self.v[vx] |= self.v[vy];
+        self.reset_vf();
         Ok(())
     }
 
     pub fn and_vx_vy(&mut self, vx: usize, vy: usize) -> Result<(), Chip8Exception> {
         debug!("AND V{:x}, V{:x}", vx, vy);
         self.v[vx] &= self.v[vy];
+        self.reset_vf();
         Ok(())
     }
 
     pub fn xor_vx_vy(&mut self, vx: usize, vy: usize) -> Result<(), Chip8Exception> {
         debug!("XOR V{:x}, V{:x}", vx, vy);
         self.v[vx] ^= self.v[vy];
+        self.reset_vf();
         Ok(())
     }
 
+    fn reset_vf(&mut self) {
+        if self.quirks.vf_reset {
+            self.v[0xF] = 0;
+        }
+    }
+
     pub fn add_vx_vy(&mut self, vx: usize, vy: usize) -> Result<(), Chip8Exception> {
         debug!("ADD V{:x}, V{:x}", vx, vy);
         let (value, overflow) = self.v[vx].overflowing_add(self.v[vy]);
@@ -350,7 +704,7 @@ impl Chip8 {
 
     pub fn shr_vx_vy(&mut self, vx: usize, vy: usize) -> Result<(), Chip8Exception> {
         debug!("SHR V{:x} {{, V{:x}}}", vx, vy);
-        let source_registry = if self.use_original_shr_shl { vy } else { vx };
+        let source_registry = if self.quirks.shift_vx { vx } else { vy };
         let (value, overflow) = self.v[source_registry].overflowing_shr(1);
         self.v[0xF] = if overflow { 1 } else { 0 };
         self.v[vx] = value;
@@ -366,7 +720,7 @@ impl Chip8 {
 
     pub fn shl_vx_vy(&mut self, vx: usize, vy: usize) -> Result<(), Chip8Exception> {
         debug!("SHL V{:x} '{{, V{:x}}}", vx, vy);
-        let source_registry = if self.use_original_shr_shl { vy } else { vx };
+        let source_registry = if self.quirks.shift_vx { vx } else { vy };
         let (value, overflow) = self.v[source_registry].overflowing_shl(1);
         self.v[0xF] = if overflow { 1 } else { 0 };
         self.v[vx] = value;
@@ -376,7 +730,7 @@ impl Chip8 {
     pub fn sne_vx_vy(&mut self, vx: usize, vy: usize) -> Result<(), Chip8Exception> {
         debug!("SNE V{:x}, V{:x}", vx, vy);
         if self.v[vx] != self.v[vy] {
-            self.pc += 2
+            self.skip_next_instruction()?;
         }
         Ok(())
     }
@@ -388,13 +742,14 @@ impl Chip8 {
     }
 
     pub fn jp_v0_addr(&mut self, addr: usize) -> Result<(), Chip8Exception> {
-        debug!("JP V0, addr({:x})", addr);
-        self.pc = addr + self.v[0 as usize] as usize;
+        let vx = if self.quirks.jump_vx { addr >> 8 } else { 0 };
+        debug!("JP V{:x}, addr({:x})", vx, addr);
+        self.pc = addr + self.v[vx] as usize;
         Ok(())
     }
 
     pub fn rnd_vx_byte(&mut self, vx: usize, byte: u8) -> Result<(), Chip8Exception> {
-        let n: u8 = rand::thread_rng().gen();
+        let n = self.rng.next_byte(&self.memory[..0x100]);
         self.v[vx] = n & byte;
         debug!("RND V{:x}, byte({:x}) => {:x}", vx, byte, self.v[vx]);
         Ok(())
@@ -407,22 +762,53 @@ impl Chip8 {
         nibble: u8,
     ) -> Result<(), Chip8Exception> {
         debug!("DRW V{:x}, V{:x}, nibble({:x})", vx, vy, nibble);
+        if self.wait_for_vblank() {
+            return Ok(());
+        }
+        self.draw_sprite(vx, vy, nibble as usize, 1)
+    }
+
+    fn draw_sprite(
+        &mut self,
+        vx: usize,
+        vy: usize,
+        rows: usize,
+        bytes_per_row: usize,
+    ) -> Result<(), Chip8Exception> {
+        let (width, height) = (self.width(), self.height());
+        // The origin always wraps, the rest of the sprite is either clipped or wrapped
+        let (x_origin, y_origin) = (self.v[vx] as usize % width, self.v[vy] as usize % height);
+        let mut sprite_addr = self.i;
         self.v[0xF] = 0;
-        for y in 0..nibble {
-            let pixel_row = self.memory[self.i + y as usize];
-            let vm_row = (self.v[vy] as usize + y as usize) % 32;
-            for x in 0..8 {
-                let vm_col = (self.v[vx] as usize + x as usize) % 64;
-                let pixel_value = (pixel_row & (0x80 >> x)) != 0;
-                if pixel_value {
-                    let position = 64 * vm_row + vm_col;
-                    if self.video_memory[position] {
-                        self.v[0xF] = 1;
+        // Each selected plane takes its own copy of the sprite data, one after the other
+        for plane in (0..MAX_PLANES).map(|plane| 1 << plane) {
+            if self.planes & plane == 0 {
+                continue;
+            }
+            for y in 0..rows {
+                if self.quirks.clip_sprites && y_origin + y >= height {
+                    break;
+                }
+                let vm_row = (y_origin + y) % height;
+                for byte in 0..bytes_per_row {
+                    let pixel_row = self.read_memory(sprite_addr + y * bytes_per_row + byte)?;
+                    for x in 0..8 {
+                        if self.quirks.clip_sprites && x_origin + 8 * byte + x >= width {
+                            break;
+                        }
+                        let vm_col = (x_origin + 8 * byte + x) % width;
+                        if pixel_row & (0x80 >> x) != 0 {
+                            let position = width * vm_row + vm_col;
+                            if self.video_memory[position] & plane != 0 {
+                                self.v[0xF] = 1;
+                            }
+                            self.video_memory_tainted = true;
+                            self.video_memory[position] ^= plane;
+                        }
                     }
-                    self.video_memory_tainted = true;
-                    self.video_memory[position] ^= pixel_value;
                 }
             }
+            sprite_addr += rows * bytes_per_row;
         }
         Ok(())
     }
@@ -430,8 +816,8 @@ impl Chip8 {
     pub fn skp_vx(&mut self, vx: usize) -> Result<(), Chip8Exception> {
         debug!("SKP V{:x}", vx);
 
-        if self.keys[self.v[vx] as usize] {
-            self.pc += 2;
+        if self.keys[(self.v[vx] & 0xF) as usize] {
+            self.skip_next_instruction()?;
         }
 
         Ok(())
@@ -439,8 +825,8 @@ impl Chip8 {
 
     pub fn sknp_vx(&mut self, vx: usize) -> Result<(), Chip8Exception> {
         debug!("SKNP V{:x}", vx);
-        if !self.keys[self.v[vx] as usize] {
-            self.pc += 2;
+        if !self.keys[(self.v[vx] & 0xF) as usize] {
+            self.skip_next_instruction()?;
         }
         Ok(())
     }
@@ -453,8 +839,7 @@ impl Chip8 {
 
     pub fn ld_vx_k(&mut self, vx: usize) -> Result<(), Chip8Exception> {
         debug!("LD V{:x}, K", vx);
-        self.waiting_for_key = true;
-        self.read_key_registry = vx;
+        self.key_wait = KeyWait::Press(vx);
         Ok(())
     }
 
@@ -473,7 +858,7 @@ impl Chip8 {
 
     pub fn add_i_vx(&mut self, vx: usize) -> Result<(), Chip8Exception> {
         debug!("ADD I, V{:x}", vx);
-        self.i += self.v[vx] as usize;
+        self.i = self.resolve_address(self.i + self.v[vx] as usize)?;
         Ok(())
     }
 
@@ -485,78 +870,205 @@ impl Chip8 {
 
     pub fn ld_b_vx(&mut self, vx: usize) -> Result<(), Chip8Exception> {
         debug!("LD B, V{:x}", vx);
-        self.memory[self.i] = (self.v[vx] / 100) as u8;
-        self.memory[self.i + 1] = (self.v[vx] as u8 - 100 * self.memory[self.i]) / 10 as u8;
-        self.memory[self.i + 2] =
-            (self.v[vx] as u8 - 100 * self.memory[self.i] - 10 * self.memory[self.i + 1]) as u8;
+        self.write_memory(self.i, self.v[vx] / 100)?;
+        self.write_memory(self.i + 1, (self.v[vx] / 10) % 10)?;
+        self.write_memory(self.i + 2, self.v[vx] % 10)?;
         Ok(())
     }
 
     pub fn ld_mem_i_vx(&mut self, vx: usize) -> Result<(), Chip8Exception> {
         debug!("LD [I], V{:x}", vx);
         for r in 0..=vx {
-            self.memory[self.i as usize + r] = self.v[r];
+            self.write_memory(self.i + r, self.v[r])?;
         }
+        self.increment_i(vx);
         Ok(())
     }
 
     pub fn ld_vx_mem_i(&mut self, vx: usize) -> Result<(), Chip8Exception> {
         debug!("LD V{:x}, [I]", vx);
         for r in 0..=vx {
-            self.v[r] = self.memory[self.i as usize + r]
+            self.v[r] = self.read_memory(self.i + r)?;
         }
+        self.increment_i(vx);
         Ok(())
     }
 
-    /*
+    fn increment_i(&mut self, vx: usize) {
+        match self.quirks.memory_increment {
+            MemoryIncrement::XPlusOne => self.i += vx + 1,
+            MemoryIncrement::X => self.i += vx,
+            MemoryIncrement::None => {}
+        }
+    }
+
     // SuperChip-8
-    pub fn scd(&mut self) { debug!("SCD nibble"); }
-    pub fn scr(&mut self) { debug!("SCR"); }
-    pub fn scl(&mut self) { debug!("SCL"); }
-    pub fn exit(&mut self) { debug!("EXIT"); }
-    pub fn low(&mut self) { debug!("LOW"); }
-    pub fn high(&mut self) { debug!("HIGH"); }
-    pub fn drw_vx_vy_0(&mut self) { debug!("DRW Vx, Vy, 0"); }
-    pub fn ld_hf_vx(&mut self) { debug!("LD HF, Vx"); }
-    pub fn ld_r_vx(&mut self) { debug!("LD R, Vx"); }
-    pub fn ld_vx_r(&mut self) { debug!("LD Vx, R"); }
-    */
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height) = (self.width() as isize, self.height() as isize);
+        let previous = self.video_memory;
+        // Only the selected planes move, the rest stay where they are
+        for y in 0..height {
+            for x in 0..width {
+                let (src_x, src_y) = (x - dx, y - dy);
+                let source = if (0..width).contains(&src_x) && (0..height).contains(&src_y) {
+                    previous[(width * src_y + src_x) as usize] & self.planes
+                } else {
+                    0
+                };
+                let position = (width * y + x) as usize;
+                self.video_memory[position] = (previous[position] & !self.planes) | source;
+            }
+        }
+        self.video_memory_tainted = true;
+    }
+
+    pub fn scd(&mut self, nibble: u8) -> Result<(), Chip8Exception> {
+        debug!("SCD nibble({:x})", nibble);
+        self.scroll(0, nibble as isize);
+        Ok(())
+    }
+
+    pub fn scr(&mut self) -> Result<(), Chip8Exception> {
+        debug!("SCR");
+        self.scroll(4, 0);
+        Ok(())
+    }
+
+    pub fn scl(&mut self) -> Result<(), Chip8Exception> {
+        debug!("SCL");
+        self.scroll(-4, 0);
+        Ok(())
+    }
+
+    pub fn exit(&mut self) -> Result<(), Chip8Exception> {
+        debug!("EXIT");
+        self.state = State::Halted;
+        Ok(())
+    }
+
+    pub fn low(&mut self) -> Result<(), Chip8Exception> {
+        debug!("LOW");
+        self.hires = false;
+        self.clear_all_planes();
+        Ok(())
+    }
+
+    pub fn high(&mut self) -> Result<(), Chip8Exception> {
+        debug!("HIGH");
+        self.hires = true;
+        self.clear_all_planes();
+        Ok(())
+    }
+
+    pub fn drw_vx_vy_0(&mut self, vx: usize, vy: usize) -> Result<(), Chip8Exception> {
+        debug!("DRW V{:x}, V{:x}, 0", vx, vy);
+        if self.wait_for_vblank() {
+            return Ok(());
+        }
+        self.draw_sprite(vx, vy, 16, 2)
+    }
+
+    pub fn ld_hf_vx(&mut self, vx: usize) -> Result<(), Chip8Exception> {
+        debug!("LD HF, V{:x}", vx);
+        self.i = self.big_font_base_addr + 10 * self.v[vx] as usize;
+        Ok(())
+    }
+
+    pub fn ld_r_vx(&mut self, vx: usize) -> Result<(), Chip8Exception> {
+        debug!("LD R, V{:x}", vx);
+        self.rpl[..=vx].copy_from_slice(&self.v[..=vx]);
+        Ok(())
+    }
+
+    pub fn ld_vx_r(&mut self, vx: usize) -> Result<(), Chip8Exception> {
+        debug!("LD V{:x}, R", vx);
+        self.v[..=vx].copy_from_slice(&self.rpl[..=vx]);
+        Ok(())
+    }
+
+    // XO-CHIP
+    pub fn scu(&mut self, nibble: u8) -> Result<(), Chip8Exception> {
+        debug!("SCU nibble({:x})", nibble);
+        self.scroll(0, -(nibble as isize));
+        Ok(())
+    }
+
+    pub fn ld_mem_i_vx_vy(&mut self, vx: usize, vy: usize) -> Result<(), Chip8Exception> {
+        debug!("LD [I], V{:x}-V{:x}", vx, vy);
+        for (offset, r) in register_range(vx, vy).enumerate() {
+            self.write_memory(self.i + offset, self.v[r])?;
+        }
+        Ok(())
+    }
+
+    pub fn ld_vx_vy_mem_i(&mut self, vx: usize, vy: usize) -> Result<(), Chip8Exception> {
+        debug!("LD V{:x}-V{:x}, [I]", vx, vy);
+        for (offset, r) in register_range(vx, vy).enumerate() {
+            self.v[r] = self.read_memory(self.i + offset)?;
+        }
+        Ok(())
+    }
+
+    pub fn ld_i_long(&mut self) -> Result<(), Chip8Exception> {
+        let addr =
+            (self.read_memory(self.pc)? as usize) << 8 | self.read_memory(self.pc + 1)? as usize;
+        debug!("LD I, long addr({:x})", addr);
+        self.i = addr;
+        self.pc += 2;
+        Ok(())
+    }
+
+    pub fn plane(&mut self, n: u8) -> Result<(), Chip8Exception> {
+        debug!("PLANE {:x}", n);
+        self.planes = n;
+        Ok(())
+    }
+
+    pub fn audio(&mut self) -> Result<(), Chip8Exception> {
+        debug!("AUDIO");
+        let mut pattern = [0; 16];
+        for (offset, byte) in pattern.iter_mut().enumerate() {
+            *byte = self.read_memory(self.i + offset)?;
+        }
+        self.audio_pattern = Some(pattern);
+        Ok(())
+    }
+
+    pub fn pitch_vx(&mut self, vx: usize) -> Result<(), Chip8Exception> {
+        debug!("PITCH V{:x}", vx);
+        self.pitch = self.v[vx];
+        Ok(())
+    }
 }
 
-use std::fmt;
+// Registers from vx to vy, in descending order if vx > vy
+fn register_range(vx: usize, vy: usize) -> Box<dyn Iterator<Item = usize>> {
+    if vx <= vy {
+        Box::new(vx..=vy)
+    } else {
+        Box::new((vy..=vx).rev())
+    }
+}
 
 impl fmt::Display for Chip8 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "=-------------------------------------------------").unwrap();
         writeln!(
             f,
-            "=PC: 0x{:0>2x}, SP: 0x{:0>2x}, DT: 0x{:0>2x}, ST: 0x{:0>2x}, I: 0x{:0>2x}, Timer counter: {:}",
-            &self.pc, &self.sp, &self.dt, &self.st, &self.i, &self.timer_counter,
+            "=PC: 0x{:0>2x}, SP: 0x{:0>2x}, DT: 0x{:0>2x}, ST: 0x{:0>2x}, I: 0x{:0>2x}",
+            &self.pc, &self.sp, &self.dt, &self.st, &self.i,
         )
         .unwrap();
         writeln!(
             f,
             "=M[PC..+32]: 0x{:0>2x?}",
-            &self.memory[self.pc..self.pc + 32]
-        )
-        .unwrap();
-        writeln!(
-            f,
-            "=M[I.. +32]: 0x{:0>2x?}",
-            &self.memory[self.i..self.i + 32]
+            self.memory_window(self.pc, 32)
         )
         .unwrap();
+        writeln!(f, "=M[I.. +32]: 0x{:0>2x?}", self.memory_window(self.i, 32)).unwrap();
         writeln!(f, "=REGS:       0x{:0>2x?}", &self.v).unwrap();
         writeln!(f, "=STACK:      0x{:0>4x?}", &self.stack).unwrap();
-        writeln!(
-            f,
-            "=VIDEO:      0b{:?}",
-            &self.video_memory[..32]
-                .iter()
-                .map(|bit| if *bit { 1 } else { 0 })
-                .collect::<Vec<u8>>()
-        )
-        .unwrap();
+        writeln!(f, "=VIDEO:      0b{:?}", &self.video_memory[..32]).unwrap();
         writeln!(
             f,
             "=KEYS:       0b{:?}",
@@ -567,6 +1079,7 @@ impl fmt::Display for Chip8 {
                 .collect::<Vec<u8>>()
         )
         .unwrap();
+        writeln!(f, "=KEY WAIT:   {:?}", self.key_wait).unwrap();
 
         write!(f, "=-------------------------------------------------")
     }
@@ -575,25 +1088,36 @@ impl fmt::Display for Chip8 {
 impl Default for Chip8 {
     fn default() -> Self {
         Self {
-            memory: [0; 4096],
+            memory: [0; XO_CHIP_MEMORY_SIZE],
             v: [0; 16],
             stack: [0; 16],
-            video_memory: [false; 64 * 32],
+            video_memory: [0; HIRES_WIDTH * HIRES_HEIGHT],
+            hires: false,
+            xo_chip: false,
+            planes: 1,
+            audio_pattern: None,
+            pitch: 64,
+            rpl: [0; 16],
             i: 0,
             dt: 0,
             st: 0,
             pc: 0,
             sp: 0,
             keys: [false; 16],
-            timer_counter: 0,
             font_base_addr: 0,
-            waiting_for_key: false,
-            read_key_registry: 0,
+            big_font_base_addr: 0x50,
+            key_wait: KeyWait::None,
+            waiting_for_vblank: false,
+            vblank: false,
             state: State::Running,
+            instructions_per_frame: 10,
+            timing: Timing::InstructionsPerFrame,
+            cycle_budget: 0,
+            memory_policy: MemoryPolicy::Strict,
             video_memory_tainted: false,
             chrono: Instant::now(),
-            use_original_shr_shl: false,
-            ignore_short_beeps: false,
+            quirks: Quirks::default(),
+            rng: Rng::from_time(RngAlgorithm::Xorshift),
         }
     }
 }
@@ -0,0 +1,92 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+
+use log::{error, info};
+
+/// Local TCP port serving one client at a time without ever blocking, so debugger servers can
+/// poll it from the frame loop. What the client sent piles up in `input`.
+pub(crate) struct Connection {
+    // Names the peer in log messages
+    peer: &'static str,
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    pub input: Vec<u8>,
+}
+
+impl Connection {
+    pub fn listen(port: u16, peer: &'static str) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        listener.set_nonblocking(true)?;
+        info!("Waiting for {} on port {}", peer, port);
+        Ok(Self {
+            peer,
+            listener,
+            client: None,
+            input: vec![],
+        })
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.client.is_some()
+    }
+
+    /// Take a waiting client, true if one just connected
+    pub fn accept(&mut self) -> bool {
+        match self.listener.accept() {
+            Ok((stream, address)) => {
+                if let Err(e) = stream.set_nonblocking(true) {
+                    error!("Unable to set up the {} connection: {}", self.peer, e);
+                    return false;
+                }
+                // Nagle would hold back every small reply
+                let _ = stream.set_nodelay(true);
+                info!("{} connected from {}", self.peer, address);
+                self.client = Some(stream);
+                self.input.clear();
+                true
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => false,
+            Err(e) => {
+                error!("Unable to accept a {} connection: {}", self.peer, e);
+                false
+            }
+        }
+    }
+
+    pub fn close(&mut self) {
+        if self.client.take().is_some() {
+            info!("{} disconnected", self.peer);
+        }
+    }
+
+    /// Read what the client sent so far into `input`, false once it hung up
+    pub fn receive(&mut self) -> bool {
+        let client = match self.client.as_mut() {
+            Some(client) => client,
+            None => return false,
+        };
+        let mut buffer = [0; 4096];
+        loop {
+            match client.read(&mut buffer) {
+                Ok(0) => return false,
+                Ok(len) => self.input.extend_from_slice(&buffer[..len]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return true,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    error!("{} connection failed: {}", self.peer, e);
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Send `data`, dropping the client if that fails
+    pub fn write(&mut self, data: &[u8]) {
+        if let Some(client) = self.client.as_mut() {
+            if let Err(e) = client.write_all(data) {
+                error!("{} connection failed: {}", self.peer, e);
+                self.client = None;
+            }
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use crate::chip8::Chip8;
+use crate::debugger::{Access, Breakpoint, DebugClient, Debugger, Location, Register, Stop};
+use crate::disasm::Instruction;
+
+const HELP: &str = "\
+c, continue          resume
+p, pause             pause
+s, step              run one instruction
+n, next              step, running calls to completion
+finish               run until the current subroutine returns
+until ADDR           run to ADDR
+b ADDR               break at ADDR
+bo PATTERN           break on opcodes matching PATTERN, e.g. 8??4 or D01F
+w ADDR[:LEN] [r|w|rw]  watch memory, writes by default
+wr REG [r|w|rw]      watch V0-VF, I, DT or ST, writes by default
+d, delete ID         remove a breakpoint or watchpoint
+l, list              list breakpoints and watchpoints
+r, regs              show the registers
+x ADDR [LEN]         dump memory
+h, help              this help";
+
+/// Debugger console reading commands from stdin. Lines are read on their own thread so polling
+/// never blocks the frame loop.
+pub struct Console {
+    debugger: Debugger,
+    lines: Receiver<String>,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        println!("Debugger paused, type help for commands");
+        Self {
+            debugger: Debugger::new(),
+            lines,
+        }
+    }
+
+    fn execute(&mut self, machine: &mut Chip8, line: &str) -> Result<(), String> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(()),
+        };
+        let args: Vec<&str> = words.collect();
+        let arg = |index: usize| args.get(index).copied().ok_or("Missing argument");
+
+        match command {
+            "c" | "continue" => self.debugger.resume(machine),
+            "p" | "pause" => self.debugger.pause(),
+            "s" | "step" => self.debugger.step(machine),
+            "n" | "next" => self.debugger.step_over(machine),
+            "finish" => {
+                if !self.debugger.step_out(machine) {
+                    return Err("Not in a subroutine".into());
+                }
+            }
+            "until" => self.debugger.run_to(machine, parse_addr(arg(0)?)?),
+            "b" | "break" => self.add(Breakpoint::Pc(parse_addr(arg(0)?)?)),
+            "bo" => {
+                let breakpoint = Breakpoint::op_code(arg(0)?).ok_or("Invalid opcode pattern")?;
+                self.add(breakpoint)
+            }
+            "w" | "watch" => {
+                let (addr, len) = match arg(0)?.split_once(':') {
+                    Some((addr, len)) => (parse_addr(addr)?, parse_number(len)?),
+                    None => (parse_addr(arg(0)?)?, 1),
+                };
+                let access = parse_access(args.get(1).copied())?;
+                self.add(Breakpoint::Watch(Location::Memory(addr, len), access))
+            }
+            "wr" => {
+                let register = Register::parse(arg(0)?).ok_or("Unknown register")?;
+                let access = parse_access(args.get(1).copied())?;
+                self.add(Breakpoint::Watch(Location::Register(register), access))
+            }
+            "d" | "delete" => {
+                let id = parse_number(arg(0)?)?;
+                if self.debugger.remove_breakpoint(id).is_none() {
+                    return Err(format!("No breakpoint {}", id));
+                }
+            }
+            "l" | "list" => {
+                for (id, breakpoint) in &self.debugger.breakpoints {
+                    println!("{}: {}", id, breakpoint);
+                }
+            }
+            "r" | "regs" => print_registers(machine),
+            "x" => {
+                let addr = parse_addr(arg(0)?)?;
+                let len = args.get(1).map_or(Ok(16), |len| parse_number(len))?;
+                let end = addr.saturating_add(len).min(machine.address_space());
+                for row in (addr..end).step_by(16) {
+                    let bytes: Vec<String> = (row..row.saturating_add(16).min(end))
+                        .map(|addr| match machine.read_memory(addr) {
+                            Ok(byte) => format!("{:02x}", byte),
+                            Err(_) => "--".into(),
+                        })
+                        .collect();
+                    println!("{:04x}: {}", row, bytes.join(" "));
+                }
+            }
+            "h" | "help" => println!("{}", HELP),
+            _ => return Err(format!("Unknown command {}, try help", command)),
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, breakpoint: Breakpoint) {
+        let id = self.debugger.add_breakpoint(breakpoint);
+        println!("{}: {}", id, breakpoint);
+    }
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DebugClient for Console {
+    fn debugger(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    fn poll(&mut self, machine: &mut Chip8) {
+        while let Ok(line) = self.lines.try_recv() {
+            if let Err(e) = self.execute(machine, &line) {
+                println!("{}", e);
+            }
+        }
+    }
+
+    fn stopped(&mut self, machine: &Chip8, stop: &Stop) {
+        match stop {
+            Stop::Breakpoint(id) => match self.debugger.breakpoints.get(id) {
+                Some(breakpoint) => println!("Hit {}: {}", id, breakpoint),
+                None => println!("Hit {}", id),
+            },
+            Stop::Fault(fault) => println!("Fault: {}", fault),
+            Stop::Step | Stop::Pause => {}
+        }
+        let bytes = &machine.memory[machine.pc.min(machine.address_space())..];
+        match (machine.peek_instruction(), Instruction::decode(bytes)) {
+            (Some(op_code), Some(instruction)) => {
+                println!("0x{:04x}: {:04x}  {}", machine.pc, op_code, instruction)
+            }
+            (Some(op_code), None) => println!("0x{:04x}: {:04x}", machine.pc, op_code),
+            (None, _) => println!("0x{:04x}: out of memory", machine.pc),
+        }
+    }
+}
+
+fn print_registers(machine: &Chip8) {
+    for (index, value) in machine.v.iter().enumerate() {
+        print!("V{:X}={:02x} ", index, value);
+    }
+    println!();
+    println!(
+        "I={:04x} PC={:04x} SP={:x} DT={:02x} ST={:02x}",
+        machine.i, machine.pc, machine.sp, machine.dt, machine.st
+    );
+    let stack: Vec<String> = machine.stack[..machine.sp]
+        .iter()
+        .map(|addr| format!("{:04x}", addr))
+        .collect();
+    println!("Stack: [{}]", stack.join(" "));
+}
+
+fn parse_number(text: &str) -> Result<usize, String> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("Invalid number {}", text))
+}
+
+// Addresses are hexadecimal with or without the 0x prefix
+fn parse_addr(text: &str) -> Result<usize, String> {
+    usize::from_str_radix(text.trim_start_matches("0x"), 16)
+        .map_err(|_| format!("Invalid address {}", text))
+}
+
+fn parse_access(text: Option<&str>) -> Result<Access, String> {
+    match text {
+        None | Some("w") => Ok(Access::Write),
+        Some("r") => Ok(Access::Read),
+        Some("rw") => Ok(Access::ReadWrite),
+        Some(other) => Err(format!("Invalid access {}, use r, w or rw", other)),
+    }
+}
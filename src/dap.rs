@@ -0,0 +1,496 @@
+use std::convert::TryFrom;
+use std::io;
+
+use log::error;
+use serde_json::{json, Value};
+
+use crate::chip8::Chip8;
+use crate::connection::Connection;
+use crate::debugger::{Breakpoint, DebugClient, Debugger, Stop};
+use crate::sourcemap::SourceMap;
+
+const THREAD_ID: u64 = 1;
+// Variable references of the scopes, the same for every stack frame
+const REGISTERS: u64 = 1;
+const STACK: u64 = 2;
+const DISPLAY: u64 = 3;
+
+/// Debug Adapter Protocol server on a local TCP port, for editors to debug the running ROM.
+/// Breakpoints set in source files go through the assembler's `SourceMap`, stepping is by
+/// instruction. The machine pauses when an editor connects and runs on once configured.
+pub struct DapServer {
+    debugger: Debugger,
+    connection: Connection,
+    seq: u64,
+    source_map: Option<SourceMap>,
+    // Breakpoint ids set by setBreakpoints per source path, and by setInstructionBreakpoints
+    source_breakpoints: Vec<(String, Vec<usize>)>,
+    instruction_breakpoints: Vec<usize>,
+    stop_on_entry: bool,
+    // Stops aren't reported until the editor is done setting breakpoints
+    configured: bool,
+}
+
+impl DapServer {
+    pub fn new(port: u16, source_map: Option<SourceMap>) -> io::Result<Self> {
+        Ok(Self {
+            debugger: Debugger::new(),
+            connection: Connection::listen(port, "DAP client")?,
+            seq: 1,
+            source_map,
+            source_breakpoints: vec![],
+            instruction_breakpoints: vec![],
+            stop_on_entry: false,
+            configured: false,
+        })
+    }
+
+    fn accept(&mut self, machine: &Chip8) {
+        if self.connection.accept() {
+            self.seq = 1;
+            self.stop_on_entry = false;
+            self.configured = false;
+            self.debugger.pause();
+        } else if self.debugger.paused() {
+            self.debugger.resume(machine);
+        }
+    }
+
+    // Drop the client's breakpoints along with it
+    fn detach(&mut self, machine: &Chip8) {
+        self.connection.close();
+        let ids: Vec<usize> = self
+            .source_breakpoints
+            .drain(..)
+            .flat_map(|(_, ids)| ids)
+            .chain(self.instruction_breakpoints.drain(..))
+            .collect();
+        for id in ids {
+            self.debugger.remove_breakpoint(id);
+        }
+        self.debugger.resume(machine);
+    }
+
+    // Next complete message in the input, framed by a Content-Length header
+    fn next_message(&mut self) -> Option<Value> {
+        loop {
+            let header_end = self
+                .connection
+                .input
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")?;
+            let header = String::from_utf8_lossy(&self.connection.input[..header_end]).into_owned();
+            let length = header
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("Content-Length"))
+                .and_then(|(_, value)| value.trim().parse::<usize>().ok());
+            let body_start = header_end + 4;
+            let length = match length {
+                Some(length) => length,
+                None => {
+                    // Skip the malformed header
+                    self.connection.input.drain(..body_start);
+                    continue;
+                }
+            };
+            if self.connection.input.len() < body_start.saturating_add(length) {
+                return None;
+            }
+            let body: Vec<u8> = self
+                .connection
+                .input
+                .drain(..body_start + length)
+                .skip(body_start)
+                .collect();
+            match serde_json::from_slice(&body) {
+                Ok(message) => return Some(message),
+                Err(e) => error!("Invalid DAP message: {}", e),
+            }
+        }
+    }
+
+    fn send(&mut self, mut message: Value) {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        let body = message.to_string();
+        let packet = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+        self.connection.write(packet.as_bytes());
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response);
+    }
+
+    fn handle(&mut self, machine: &mut Chip8, request: &Value) {
+        let arguments = &request["arguments"];
+        let result = match request["command"].as_str().unwrap_or_default() {
+            "initialize" => {
+                let capabilities = json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsInstructionBreakpoints": true,
+                    "supportsReadMemoryRequest": true,
+                    "supportsSetVariable": true,
+                    "supportsSteppingGranularity": true,
+                });
+                self.respond(request, Ok(capabilities));
+                self.event("initialized", json!({}));
+                return;
+            }
+            "launch" | "attach" => {
+                // The ROM is already loaded, both just attach to it
+                self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+                Ok(json!({}))
+            }
+            "configurationDone" => {
+                self.configured = true;
+                self.respond(request, Ok(json!({})));
+                if self.stop_on_entry {
+                    self.report_stop("entry", None);
+                } else {
+                    self.debugger.resume(machine);
+                }
+                return;
+            }
+            "setBreakpoints" => self.set_breakpoints(arguments),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(arguments),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }] })),
+            "stackTrace" => Ok(self.stack_trace(machine)),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+                { "name": "Stack", "variablesReference": STACK, "expensive": false },
+                { "name": "Display", "variablesReference": DISPLAY, "expensive": false },
+            ]})),
+            "variables" => variables(machine, arguments["variablesReference"].as_u64()),
+            "setVariable" => set_variable(machine, arguments),
+            "readMemory" => read_memory(machine, arguments),
+            "continue" => {
+                self.debugger.resume(machine);
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" => {
+                self.debugger.step_over(machine);
+                Ok(json!({}))
+            }
+            "stepIn" => {
+                self.debugger.step(machine);
+                Ok(json!({}))
+            }
+            "stepOut" => {
+                if !self.debugger.step_out(machine) {
+                    self.debugger.step(machine);
+                }
+                Ok(json!({}))
+            }
+            "pause" => {
+                self.debugger.pause();
+                Ok(json!({}))
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(json!({})));
+                self.detach(machine);
+                return;
+            }
+            command => Err(format!("Unsupported request {}", command)),
+        };
+        self.respond(request, result);
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        let path = arguments["source"]["path"]
+            .as_str()
+            .ok_or("Source has no path")?
+            .to_string();
+        if let Some(index) = self.source_breakpoints.iter().position(|(p, _)| *p == path) {
+            let (_, ids) = self.source_breakpoints.remove(index);
+            for id in ids {
+                self.debugger.remove_breakpoint(id);
+            }
+        }
+
+        let mut ids = vec![];
+        let mut breakpoints = vec![];
+        let requested = arguments["breakpoints"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        for breakpoint in requested {
+            let line = breakpoint["line"].as_u64().unwrap_or_default() as usize;
+            let location = self
+                .source_map
+                .as_ref()
+                .and_then(|source_map| source_map.address(&path, line))
+                .cloned();
+            breakpoints.push(match location {
+                Some(location) => {
+                    let id = self.debugger.add_breakpoint(Breakpoint::Pc(location.addr));
+                    ids.push(id);
+                    json!({ "id": id, "verified": true, "line": location.line })
+                }
+                None => json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "No code on this line in the source map",
+                }),
+            });
+        }
+        self.source_breakpoints.push((path, ids));
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn set_instruction_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        for id in self.instruction_breakpoints.drain(..) {
+            self.debugger.remove_breakpoint(id);
+        }
+
+        let mut breakpoints = vec![];
+        let requested = arguments["breakpoints"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        for breakpoint in requested {
+            let addr = breakpoint["instructionReference"]
+                .as_str()
+                .and_then(parse_addr)
+                .map(|addr| {
+                    (addr as i64).saturating_add(breakpoint["offset"].as_i64().unwrap_or(0))
+                });
+            breakpoints.push(match addr {
+                Some(addr) if addr >= 0 => {
+                    let id = self.debugger.add_breakpoint(Breakpoint::Pc(addr as usize));
+                    self.instruction_breakpoints.push(id);
+                    json!({ "id": id, "verified": true })
+                }
+                _ => json!({ "verified": false, "message": "Invalid instruction reference" }),
+            });
+        }
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    // The current instruction, then the call of every subroutine on the stack
+    fn stack_trace(&self, machine: &Chip8) -> Value {
+        let calls = machine.stack[..machine.sp]
+            .iter()
+            .rev()
+            .map(|ret| ret.saturating_sub(2));
+        let frames: Vec<Value> = std::iter::once(machine.pc)
+            .chain(calls)
+            .enumerate()
+            .map(|(id, addr)| {
+                let mut frame = json!({
+                    "id": id,
+                    "name": format!("0x{:04x}", addr),
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("0x{:04x}", addr),
+                });
+                if let Some(location) = self.source_map.as_ref().and_then(|map| map.location(addr))
+                {
+                    frame["source"] = json!({ "path": location.file });
+                    frame["line"] = json!(location.line);
+                    frame["column"] = json!(1);
+                }
+                frame
+            })
+            .collect();
+        json!({ "stackFrames": frames, "totalFrames": frames.len() })
+    }
+
+    fn report_stop(&mut self, reason: &str, text: Option<&str>) {
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(text) = text {
+            body["text"] = json!(text);
+        }
+        self.event("stopped", body);
+    }
+}
+
+impl DebugClient for DapServer {
+    fn debugger(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    fn poll(&mut self, machine: &mut Chip8) {
+        if !self.connection.is_connected() {
+            self.accept(machine);
+            return;
+        }
+        if !self.connection.receive() {
+            self.detach(machine);
+            return;
+        }
+        while let Some(request) = self.next_message() {
+            if request["type"] == "request" {
+                self.handle(machine, &request);
+            }
+            if !self.connection.is_connected() {
+                break;
+            }
+        }
+    }
+
+    fn stopped(&mut self, _machine: &Chip8, stop: &Stop) {
+        if !self.connection.is_connected() || !self.configured {
+            return;
+        }
+        match stop {
+            Stop::Step => self.report_stop("step", None),
+            Stop::Pause => self.report_stop("pause", None),
+            Stop::Breakpoint(id) => {
+                let reason = match self.debugger.breakpoints.get(id) {
+                    Some(Breakpoint::Pc(_)) if self.instruction_breakpoints.contains(id) => {
+                        "instruction breakpoint"
+                    }
+                    _ => "breakpoint",
+                };
+                self.report_stop(reason, None);
+            }
+            Stop::Fault(fault) => self.report_stop("exception", Some(fault)),
+        }
+    }
+}
+
+fn variables(machine: &Chip8, reference: Option<u64>) -> Result<Value, String> {
+    let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+    let variables: Vec<Value> = match reference {
+        Some(REGISTERS) => {
+            let mut registers: Vec<Value> = machine
+                .v
+                .iter()
+                .enumerate()
+                .map(|(x, value)| variable(format!("V{:X}", x), format!("0x{:02x}", value)))
+                .collect();
+            let mut i = variable("I".into(), format!("0x{:04x}", machine.i));
+            i["memoryReference"] = json!(format!("0x{:04x}", machine.i));
+            registers.push(i);
+            registers.push(variable("PC".into(), format!("0x{:04x}", machine.pc)));
+            registers.push(variable("SP".into(), machine.sp.to_string()));
+            registers.push(variable("DT".into(), machine.dt.to_string()));
+            registers.push(variable("ST".into(), machine.st.to_string()));
+            registers
+        }
+        Some(STACK) => machine.stack[..machine.sp]
+            .iter()
+            .enumerate()
+            .map(|(level, addr)| variable(format!("[{}]", level), format!("0x{:04x}", addr)))
+            .collect(),
+        Some(DISPLAY) => (0..machine.height())
+            .map(|y| {
+                let row: String = (0..machine.width())
+                    .map(|x| match machine.get_pixel(x, y) {
+                        0 => ' ',
+                        _ => '█',
+                    })
+                    .collect();
+                variable(format!("{:02}", y), row)
+            })
+            .collect(),
+        _ => return Err("Unknown variables reference".into()),
+    };
+    Ok(json!({ "variables": variables }))
+}
+
+fn set_variable(machine: &mut Chip8, arguments: &Value) -> Result<Value, String> {
+    if arguments["variablesReference"].as_u64() != Some(REGISTERS) {
+        return Err("Only registers can be changed".into());
+    }
+    let name = arguments["name"].as_str().unwrap_or_default();
+    let text = arguments["value"].as_str().unwrap_or_default().trim();
+    let value = parse_addr(text)
+        .filter(|_| text.starts_with("0x"))
+        .or_else(|| text.parse().ok())
+        .ok_or(format!("Invalid value {}", text))?;
+
+    let byte = u8::try_from(value).map_err(|_| format!("{} doesn't fit in a byte", text));
+    let shown = match name {
+        "I" => {
+            machine.i = value;
+            format!("0x{:04x}", value)
+        }
+        "PC" => {
+            machine.pc = value;
+            format!("0x{:04x}", value)
+        }
+        "DT" => {
+            machine.dt = byte?;
+            value.to_string()
+        }
+        "ST" => {
+            machine.st = byte?;
+            value.to_string()
+        }
+        _ => {
+            let x = name
+                .strip_prefix('V')
+                .and_then(|x| usize::from_str_radix(x, 16).ok())
+                .filter(|x| *x < 16)
+                .ok_or(format!("{} can't be changed", name))?;
+            machine.v[x] = byte?;
+            format!("0x{:02x}", value)
+        }
+    };
+    Ok(json!({ "value": shown }))
+}
+
+fn read_memory(machine: &Chip8, arguments: &Value) -> Result<Value, String> {
+    let addr = arguments["memoryReference"]
+        .as_str()
+        .and_then(parse_addr)
+        .ok_or("Invalid memory reference")? as i64;
+    let addr = addr.saturating_add(arguments["offset"].as_i64().unwrap_or(0));
+    let count = arguments["count"].as_u64().unwrap_or(0) as usize;
+    let address_space = machine.address_space();
+    if addr < 0 || addr as usize >= address_space {
+        return Ok(json!({
+            "address": format!("0x{:04x}", addr.max(0)),
+            "unreadableBytes": count,
+        }));
+    }
+    let start = addr as usize;
+    let end = start.saturating_add(count).min(address_space);
+    Ok(json!({
+        "address": format!("0x{:04x}", start),
+        "data": base64(&machine.memory[start..end]),
+        "unreadableBytes": count - (end - start),
+    }))
+}
+
+fn parse_addr(text: &str) -> Option<usize> {
+    usize::from_str_radix(text.trim_start_matches("0x"), 16).ok()
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| {
+            bits | (*byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
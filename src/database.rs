@@ -0,0 +1,115 @@
+use serde_json::Value;
+use sha1_smol::Sha1;
+
+use crate::quirks::{MemoryIncrement, Platform, Quirks};
+
+// Same layout as the community CHIP-8 database (https://github.com/chip-8/chip-8-database)
+static SHA1_HASHES: &str = include_str!("../db/sha1-hashes.json");
+static PROGRAMS: &str = include_str!("../db/programs.json");
+
+pub struct RomInfo {
+    pub title: String,
+    pub platform: Option<Platform>,
+    pub quirks: Option<Quirks>,
+    // Instructions per 60 Hz frame
+    pub tickrate: Option<u32>,
+    pub keys: Vec<(String, u8)>,
+    pub colors: Vec<(u8, u8, u8)>,
+}
+
+pub fn hash(rom: &[u8]) -> String {
+    Sha1::from(rom).digest().to_string()
+}
+
+pub fn lookup(rom: &[u8]) -> Option<RomInfo> {
+    let hash = hash(rom);
+    let hashes: Value = serde_json::from_str(SHA1_HASHES).ok()?;
+    let programs: Value = serde_json::from_str(PROGRAMS).ok()?;
+
+    let program = programs.get(hashes.get(&hash)?.as_u64()? as usize)?;
+    let rom = program.get("roms")?.get(&hash)?;
+
+    let platform_id = rom["platforms"].get(0).and_then(Value::as_str);
+    let platform = platform_id.and_then(platform_from_id);
+    let quirks = platform_id
+        .and_then(|id| rom.get("quirkyPlatforms")?.get(id))
+        .map(|overrides| {
+            apply_quirks(
+                platform.map(Platform::quirks).unwrap_or_default(),
+                overrides,
+            )
+        })
+        .or_else(|| platform.map(Platform::quirks));
+
+    let keys = rom
+        .get("keys")
+        .and_then(Value::as_object)
+        .map(|keys| {
+            keys.iter()
+                .filter_map(|(action, key)| Some((action.clone(), key.as_u64()? as u8)))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let colors = rom["colors"]["pixels"]
+        .as_array()
+        .map(|pixels| {
+            pixels
+                .iter()
+                .filter_map(|color| parse_color(color.as_str()?))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Some(RomInfo {
+        title: program["title"].as_str().unwrap_or("Unknown").to_string(),
+        platform,
+        quirks,
+        tickrate: rom["tickrate"].as_u64().map(|tickrate| tickrate as u32),
+        keys,
+        colors,
+    })
+}
+
+fn platform_from_id(id: &str) -> Option<Platform> {
+    match id {
+        "originalChip8" | "hybridVIP" => Some(Platform::CosmacVip),
+        "chip48" => Some(Platform::Chip48),
+        "superchip1" => Some(Platform::SuperChipLegacy),
+        "superchip" => Some(Platform::SuperChipModern),
+        "xochip" => Some(Platform::XoChip),
+        _ => None,
+    }
+}
+
+fn apply_quirks(mut quirks: Quirks, overrides: &Value) -> Quirks {
+    let flag = |name: &str| overrides.get(name).and_then(Value::as_bool);
+
+    if let Some(shift) = flag("shift") {
+        quirks.shift_vx = shift;
+    }
+    if flag("memoryIncrementByX") == Some(true) {
+        quirks.memory_increment = MemoryIncrement::X;
+    }
+    if flag("memoryLeaveIUnchanged") == Some(true) {
+        quirks.memory_increment = MemoryIncrement::None;
+    }
+    if let Some(wrap) = flag("wrap") {
+        quirks.clip_sprites = !wrap;
+    }
+    if let Some(jump) = flag("jump") {
+        quirks.jump_vx = jump;
+    }
+    if let Some(vblank) = flag("vblank") {
+        quirks.display_wait = vblank;
+    }
+    if let Some(logic) = flag("logic") {
+        quirks.vf_reset = logic;
+    }
+    quirks
+}
+
+fn parse_color(color: &str) -> Option<(u8, u8, u8)> {
+    let rgb = u32::from_str_radix(color.strip_prefix('#')?, 16).ok()?;
+    Some(((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8))
+}
@@ -0,0 +1,424 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::chip8::{Chip8, Chip8Exception};
+use crate::quirks::MemoryIncrement;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Register {
+    V(usize),
+    I,
+    Dt,
+    St,
+}
+
+impl Register {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "i" => Some(Register::I),
+            "dt" => Some(Register::Dt),
+            "st" => Some(Register::St),
+            name => {
+                let index = name.strip_prefix('v')?;
+                let index = usize::from_str_radix(index, 16).ok().filter(|i| *i < 16)?;
+                Some(Register::V(index))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Register::V(x) => write!(f, "V{:X}", x),
+            Register::I => write!(f, "I"),
+            Register::Dt => write!(f, "DT"),
+            Register::St => write!(f, "ST"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn matches(self, access: Access) -> bool {
+        self == Access::ReadWrite || self == access
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Location {
+    Register(Register),
+    /// Start address and length
+    Memory(usize, usize),
+}
+
+impl Location {
+    fn overlaps(self, other: Location) -> bool {
+        match (self, other) {
+            (Location::Register(a), Location::Register(b)) => a == b,
+            (Location::Memory(a, a_len), Location::Memory(b, b_len)) => {
+                a < b.saturating_add(b_len) && b < a.saturating_add(a_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Breakpoint {
+    /// Stop before executing the instruction at this address
+    Pc(usize),
+    /// Stop before executing an instruction for which `op_code & mask == value`
+    OpCode { value: u16, mask: u16 },
+    /// Stop before executing an instruction that accesses this location
+    Watch(Location, Access),
+}
+
+impl Breakpoint {
+    /// Parse an opcode pattern such as `D01F` or `8XY4`, anything but a hex digit is a wildcard
+    pub fn op_code(pattern: &str) -> Option<Self> {
+        if pattern.chars().count() != 4 {
+            return None;
+        }
+        let (mut value, mut mask) = (0, 0);
+        for c in pattern.chars() {
+            value <<= 4;
+            mask <<= 4;
+            if let Some(nibble) = c.to_digit(16) {
+                value |= nibble as u16;
+                mask |= 0xF;
+            }
+        }
+        Some(Breakpoint::OpCode { value, mask })
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Breakpoint::Pc(addr) => write!(f, "PC 0x{:04x}", addr),
+            Breakpoint::OpCode { value, mask } => {
+                let pattern: String = (0..4)
+                    .rev()
+                    .map(|nibble| match (mask >> (nibble * 4)) & 0xF {
+                        0 => '?',
+                        _ => std::char::from_digit(((value >> (nibble * 4)) & 0xF) as u32, 16)
+                            .unwrap()
+                            .to_ascii_uppercase(),
+                    })
+                    .collect();
+                write!(f, "opcode {}", pattern)
+            }
+            Breakpoint::Watch(Location::Register(register), access) => {
+                write!(f, "{:?} watch on {}", access, register)
+            }
+            Breakpoint::Watch(Location::Memory(addr, len), access) => write!(
+                f,
+                "{:?} watch on 0x{:04x}..0x{:04x}",
+                access,
+                addr,
+                addr.saturating_add(*len)
+            ),
+        }
+    }
+}
+
+/// Why the debugger stopped the machine
+#[derive(Clone, PartialEq, Debug)]
+pub enum Stop {
+    /// A step, step over, step out or run to cursor finished
+    Step,
+    /// Asked to pause
+    Pause,
+    Breakpoint(usize),
+    Fault(String),
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Mode {
+    Paused,
+    Running,
+    Step,
+    /// Run until PC is back at the instruction after a call, at the same stack depth
+    StepOver {
+        pc: usize,
+        sp: usize,
+    },
+    /// Run until the stack is shallower than `sp`
+    StepOut {
+        sp: usize,
+    },
+    RunTo(usize),
+}
+
+/// Breakpoints and stepping on top of `Chip8::run_frame_until`. The machine only runs when the
+/// debugger is told to continue or step, debugger frontends drive it through `DebugClient`.
+pub struct Debugger {
+    pub breakpoints: BTreeMap<usize, Breakpoint>,
+    next_id: usize,
+    mode: Mode,
+    // Breakpoints at the instruction the machine stopped on don't fire again when resuming
+    resume_pc: Option<usize>,
+    stop: Option<Stop>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: BTreeMap::new(),
+            next_id: 1,
+            mode: Mode::Paused,
+            resume_pc: None,
+            stop: None,
+        }
+    }
+
+    /// Add a breakpoint and return its id
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.insert(id, breakpoint);
+        id
+    }
+
+    pub fn remove_breakpoint(&mut self, id: usize) -> Option<Breakpoint> {
+        self.breakpoints.remove(&id)
+    }
+
+    pub fn paused(&self) -> bool {
+        self.mode == Mode::Paused
+    }
+
+    pub fn pause(&mut self) {
+        if !self.paused() {
+            self.mode = Mode::Paused;
+            self.stop = Some(Stop::Pause);
+        }
+    }
+
+    pub fn resume(&mut self, machine: &Chip8) {
+        self.start(machine, Mode::Running);
+    }
+
+    pub fn step(&mut self, machine: &Chip8) {
+        self.start(machine, Mode::Step);
+    }
+
+    /// Step, running a whole subroutine if the instruction is a call
+    pub fn step_over(&mut self, machine: &Chip8) {
+        let mode = match machine.peek_instruction() {
+            Some(op_code) if op_code & 0xF000 == 0x2000 => Mode::StepOver {
+                pc: machine.pc + 2,
+                sp: machine.sp,
+            },
+            _ => Mode::Step,
+        };
+        self.start(machine, mode);
+    }
+
+    /// Run until the current subroutine returns, false outside of a subroutine
+    pub fn step_out(&mut self, machine: &Chip8) -> bool {
+        if machine.sp == 0 {
+            return false;
+        }
+        self.start(machine, Mode::StepOut { sp: machine.sp });
+        true
+    }
+
+    pub fn run_to(&mut self, machine: &Chip8, addr: usize) {
+        self.start(machine, Mode::RunTo(addr));
+    }
+
+    fn start(&mut self, machine: &Chip8, mode: Mode) {
+        self.mode = mode;
+        // While FX0A waits PC is already past it, the next instruction hasn't been checked yet
+        self.resume_pc = Some(machine.pc).filter(|_| !machine.waiting_for_key());
+        self.stop = None;
+    }
+
+    /// Run one frame of `machine` unless paused, returns why the machine stopped if it did. A
+    /// fault pauses the debugger.
+    pub fn run_frame(
+        &mut self,
+        machine: &mut Chip8,
+        input: &[bool; 16],
+    ) -> Result<Option<Stop>, Chip8Exception> {
+        let result = match self.mode {
+            Mode::Paused => return Ok(self.stop.take()),
+            Mode::Step => {
+                self.mode = Mode::Paused;
+                self.stop = Some(Stop::Step);
+                machine.step(input)
+            }
+            _ => match machine.run_frame_until(input, |machine| self.should_stop(machine)) {
+                Ok(true) => {
+                    self.mode = Mode::Paused;
+                    Ok(())
+                }
+                result => result.map(|_| ()),
+            },
+        };
+
+        if result.is_err() {
+            // The caller reports the fault itself
+            self.mode = Mode::Paused;
+            self.stop = None;
+        }
+        result.map(|_| self.stop.take())
+    }
+
+    fn should_stop(&mut self, machine: &Chip8) -> bool {
+        // PC is already past FX0A and DXYN doesn't count until it draws
+        if machine.waiting_for_key() || machine.waiting_for_vblank {
+            return false;
+        }
+        if self.resume_pc.take() == Some(machine.pc) {
+            return false;
+        }
+
+        let done = match self.mode {
+            Mode::StepOver { pc, sp } => machine.pc == pc && machine.sp == sp,
+            Mode::StepOut { sp } => machine.sp < sp,
+            Mode::RunTo(addr) => machine.pc == addr,
+            _ => false,
+        };
+        if done {
+            self.stop = Some(Stop::Step);
+            return true;
+        }
+
+        let op_code = match machine.peek_instruction() {
+            Some(op_code) => op_code,
+            None => return false,
+        };
+        let accesses = accesses(machine, op_code);
+        let hit = self
+            .breakpoints
+            .iter()
+            .find(|(_, breakpoint)| match breakpoint {
+                Breakpoint::Pc(addr) => machine.pc == *addr,
+                Breakpoint::OpCode { value, mask } => op_code & mask == *value,
+                Breakpoint::Watch(location, access) => accesses
+                    .iter()
+                    .any(|(at, how)| location.overlaps(*at) && access.matches(*how)),
+            });
+        match hit {
+            Some((id, _)) => {
+                self.stop = Some(Stop::Breakpoint(*id));
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Registers and memory `op_code` reads and writes with the machine in its current state
+pub fn accesses(machine: &Chip8, op_code: u16) -> Vec<(Location, Access)> {
+    use Access::{Read, Write};
+    let reg = |register| Location::Register(register);
+    let x = ((op_code & 0x0F00) >> 8) as usize;
+    let y = ((op_code & 0x00F0) >> 4) as usize;
+    let n = (op_code & 0x000F) as usize;
+    let (vx, vy, vf) = (
+        reg(Register::V(x)),
+        reg(Register::V(y)),
+        reg(Register::V(0xF)),
+    );
+    let i = reg(Register::I);
+    let quirks = &machine.quirks;
+    let memory_increment = quirks.memory_increment != MemoryIncrement::None;
+    let planes = machine.planes.count_ones() as usize;
+
+    let mut accesses = match (op_code & 0xF000, n, op_code & 0x00FF) {
+        (0x3000, _, _) | (0x4000, _, _) => vec![(vx, Read)],
+        (0x5000, 0, _) | (0x9000, 0, _) => vec![(vx, Read), (vy, Read)],
+        (0x5000, 2, _) | (0x5000, 3, _) => {
+            let registers = x.min(y)..=x.max(y);
+            let memory = Location::Memory(machine.i, registers.clone().count());
+            let (to, from) = if n == 2 { (Write, Read) } else { (Read, Write) };
+            registers
+                .map(|r| (reg(Register::V(r)), from))
+                .chain([(i, Read), (memory, to)])
+                .collect()
+        }
+        (0x6000, _, _) => vec![(vx, Write)],
+        (0x7000, _, _) => vec![(vx, Read), (vx, Write)],
+        (0x8000, 0, _) => vec![(vy, Read), (vx, Write)],
+        (0x8000, 1..=3, _) if quirks.vf_reset => {
+            vec![(vx, Read), (vy, Read), (vx, Write), (vf, Write)]
+        }
+        (0x8000, 1..=3, _) => vec![(vx, Read), (vy, Read), (vx, Write)],
+        (0x8000, 4 | 5 | 7, _) => vec![(vx, Read), (vy, Read), (vx, Write), (vf, Write)],
+        (0x8000, 6 | 0xE, _) if quirks.shift_vx => vec![(vx, Read), (vx, Write), (vf, Write)],
+        (0x8000, 6 | 0xE, _) => vec![(vy, Read), (vx, Write), (vf, Write)],
+        (0xA000, _, _) => vec![(i, Write)],
+        (0xB000, _, _) if quirks.jump_vx => vec![(vx, Read)],
+        (0xB000, _, _) => vec![(reg(Register::V(0)), Read)],
+        (0xC000, _, _) => vec![(vx, Write)],
+        (0xD000, _, _) => {
+            let sprite_len = if n == 0 { 32 } else { n };
+            let memory = Location::Memory(machine.i, sprite_len * planes);
+            vec![
+                (vx, Read),
+                (vy, Read),
+                (i, Read),
+                (memory, Read),
+                (vf, Write),
+            ]
+        }
+        (0xE000, _, 0x9E) | (0xE000, _, 0xA1) => vec![(vx, Read)],
+        (0xF000, _, 0x00) if x == 0 => vec![(i, Write)],
+        (0xF000, _, 0x07) => vec![(reg(Register::Dt), Read), (vx, Write)],
+        (0xF000, _, 0x0A) => vec![(vx, Write)],
+        (0xF000, _, 0x15) => vec![(vx, Read), (reg(Register::Dt), Write)],
+        (0xF000, _, 0x18) => vec![(vx, Read), (reg(Register::St), Write)],
+        (0xF000, _, 0x1E) => vec![(vx, Read), (i, Read), (i, Write)],
+        (0xF000, _, 0x29) | (0xF000, _, 0x30) => vec![(vx, Read), (i, Write)],
+        (0xF000, _, 0x33) => vec![
+            (vx, Read),
+            (i, Read),
+            (Location::Memory(machine.i, 3), Write),
+        ],
+        (0xF000, _, 0x55) => (0..=x)
+            .map(|r| (reg(Register::V(r)), Read))
+            .chain([(i, Read), (Location::Memory(machine.i, x + 1), Write)])
+            .collect(),
+        (0xF000, _, 0x65) => (0..=x)
+            .map(|r| (reg(Register::V(r)), Write))
+            .chain([(i, Read), (Location::Memory(machine.i, x + 1), Read)])
+            .collect(),
+        (0xF000, _, 0x75) => (0..=x).map(|r| (reg(Register::V(r)), Read)).collect(),
+        (0xF000, _, 0x85) => (0..=x).map(|r| (reg(Register::V(r)), Write)).collect(),
+        (0xF000, _, 0x3A) => vec![(vx, Read)],
+        (0xF000, 2, 0x02) if x == 0 => vec![(i, Read), (Location::Memory(machine.i, 16), Read)],
+        _ => vec![],
+    };
+
+    if memory_increment && matches!(op_code & 0xF0FF, 0xF055 | 0xF065) {
+        accesses.push((i, Write));
+    }
+    accesses
+}
+
+/// Something that controls a `Debugger`, like a console or a remote debugging protocol. `run`
+/// polls it once per frame and tells it whenever the machine stops.
+pub trait DebugClient {
+    fn debugger(&mut self) -> &mut Debugger;
+
+    /// Handle the requests received since the last poll
+    fn poll(&mut self, machine: &mut Chip8);
+
+    fn stopped(&mut self, machine: &Chip8, stop: &Stop);
+}
@@ -0,0 +1,363 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// A decoded CHIP-8, SUPER-CHIP or XO-CHIP instruction. Registers are indexes into `Chip8::v`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Instruction {
+    Cls,
+    Ret,
+    Scd(u8),
+    Scu(u8),
+    Scr,
+    Scl,
+    Exit,
+    Low,
+    High,
+    Sys(usize),
+    Jp(usize),
+    Call(usize),
+    SeVxByte(usize, u8),
+    SneVxByte(usize, u8),
+    SeVxVy(usize, usize),
+    LdMemIVxVy(usize, usize),
+    LdVxVyMemI(usize, usize),
+    LdVxByte(usize, u8),
+    AddVxByte(usize, u8),
+    LdVxVy(usize, usize),
+    OrVxVy(usize, usize),
+    AndVxVy(usize, usize),
+    XorVxVy(usize, usize),
+    AddVxVy(usize, usize),
+    SubVxVy(usize, usize),
+    ShrVxVy(usize, usize),
+    SubnVxVy(usize, usize),
+    ShlVxVy(usize, usize),
+    SneVxVy(usize, usize),
+    LdIAddr(usize),
+    JpV0Addr(usize),
+    RndVxByte(usize, u8),
+    DrwVxVyNibble(usize, usize, u8),
+    SkpVx(usize),
+    SknpVx(usize),
+    /// F000 NNNN, the only 4 byte instruction
+    LdILong(usize),
+    Plane(u8),
+    Audio,
+    LdVxDt(usize),
+    LdVxK(usize),
+    LdDtVx(usize),
+    LdStVx(usize),
+    AddIVx(usize),
+    LdFVx(usize),
+    LdHfVx(usize),
+    LdBVx(usize),
+    PitchVx(usize),
+    LdMemIVx(usize),
+    LdVxMemI(usize),
+    LdRVx(usize),
+    LdVxR(usize),
+}
+
+impl Instruction {
+    /// Decode the instruction at the start of `bytes`, None if they aren't an instruction
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let op_code = u16::from_be_bytes([*bytes.first()?, *bytes.get(1)?]);
+        let nibbles = [
+            ((op_code & 0xf000) >> 12) as usize,
+            ((op_code & 0x0f00) >> 8) as usize,
+            ((op_code & 0x00f0) >> 4) as usize,
+            (op_code & 0x000f) as usize,
+        ];
+        let nnn = (op_code & 0x0FFF) as usize;
+        let kk = (op_code & 0x00FF) as u8;
+
+        use Instruction::*;
+        let instruction = match nibbles {
+            [0x0, 0x0, 0xE, 0x0] => Cls,
+            [0x0, 0x0, 0xE, 0xE] => Ret,
+            [0x0, 0x0, 0xC, n] => Scd(n as u8),
+            [0x0, 0x0, 0xD, n] => Scu(n as u8),
+            [0x0, 0x0, 0xF, 0xB] => Scr,
+            [0x0, 0x0, 0xF, 0xC] => Scl,
+            [0x0, 0x0, 0xF, 0xD] => Exit,
+            [0x0, 0x0, 0xF, 0xE] => Low,
+            [0x0, 0x0, 0xF, 0xF] => High,
+            [0x0, _, _, _] => Sys(nnn),
+            [0x1, _, _, _] => Jp(nnn),
+            [0x2, _, _, _] => Call(nnn),
+            [0x3, vx, _, _] => SeVxByte(vx, kk),
+            [0x4, vx, _, _] => SneVxByte(vx, kk),
+            [0x5, vx, vy, 0x0] => SeVxVy(vx, vy),
+            [0x5, vx, vy, 0x2] => LdMemIVxVy(vx, vy),
+            [0x5, vx, vy, 0x3] => LdVxVyMemI(vx, vy),
+            [0x6, vx, _, _] => LdVxByte(vx, kk),
+            [0x7, vx, _, _] => AddVxByte(vx, kk),
+            [0x8, vx, vy, 0x0] => LdVxVy(vx, vy),
+            [0x8, vx, vy, 0x1] => OrVxVy(vx, vy),
+            [0x8, vx, vy, 0x2] => AndVxVy(vx, vy),
+            [0x8, vx, vy, 0x3] => XorVxVy(vx, vy),
+            [0x8, vx, vy, 0x4] => AddVxVy(vx, vy),
+            [0x8, vx, vy, 0x5] => SubVxVy(vx, vy),
+            [0x8, vx, vy, 0x6] => ShrVxVy(vx, vy),
+            [0x8, vx, vy, 0x7] => SubnVxVy(vx, vy),
+            [0x8, vx, vy, 0xE] => ShlVxVy(vx, vy),
+            [0x9, vx, vy, 0x0] => SneVxVy(vx, vy),
+            [0xA, _, _, _] => LdIAddr(nnn),
+            [0xB, _, _, _] => JpV0Addr(nnn),
+            [0xC, vx, _, _] => RndVxByte(vx, kk),
+            [0xD, vx, vy, n] => DrwVxVyNibble(vx, vy, n as u8),
+            [0xE, vx, 0x9, 0xE] => SkpVx(vx),
+            [0xE, vx, 0xA, 0x1] => SknpVx(vx),
+            [0xF, 0x0, 0x0, 0x0] => {
+                LdILong(u16::from_be_bytes([*bytes.get(2)?, *bytes.get(3)?]) as usize)
+            }
+            [0xF, n, 0x0, 0x1] => Plane(n as u8),
+            [0xF, 0x0, 0x0, 0x2] => Audio,
+            [0xF, vx, 0x0, 0x7] => LdVxDt(vx),
+            [0xF, vx, 0x0, 0xA] => LdVxK(vx),
+            [0xF, vx, 0x1, 0x5] => LdDtVx(vx),
+            [0xF, vx, 0x1, 0x8] => LdStVx(vx),
+            [0xF, vx, 0x1, 0xE] => AddIVx(vx),
+            [0xF, vx, 0x2, 0x9] => LdFVx(vx),
+            [0xF, vx, 0x3, 0x0] => LdHfVx(vx),
+            [0xF, vx, 0x3, 0x3] => LdBVx(vx),
+            [0xF, vx, 0x3, 0xA] => PitchVx(vx),
+            [0xF, vx, 0x5, 0x5] => LdMemIVx(vx),
+            [0xF, vx, 0x6, 0x5] => LdVxMemI(vx),
+            [0xF, vx, 0x7, 0x5] => LdRVx(vx),
+            [0xF, vx, 0x8, 0x5] => LdVxR(vx),
+            _ => return None,
+        };
+        Some(instruction)
+    }
+
+    /// Size in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            Instruction::LdILong(_) => 4,
+            _ => 2,
+        }
+    }
+
+    /// Address operand of jumps, calls and `LD I`
+    pub fn target(&self) -> Option<usize> {
+        match *self {
+            Instruction::Sys(addr)
+            | Instruction::Jp(addr)
+            | Instruction::Call(addr)
+            | Instruction::LdIAddr(addr)
+            | Instruction::JpV0Addr(addr)
+            | Instruction::LdILong(addr) => Some(addr),
+            _ => None,
+        }
+    }
+
+    /// SE, SNE, SKP and SKNP, which may skip the next instruction
+    pub fn is_skip(&self) -> bool {
+        matches!(
+            self,
+            Instruction::SeVxByte(..)
+                | Instruction::SneVxByte(..)
+                | Instruction::SeVxVy(..)
+                | Instruction::SneVxVy(..)
+                | Instruction::SkpVx(_)
+                | Instruction::SknpVx(_)
+        )
+    }
+
+    /// Mnemonic with `addr` naming the address operand, if any
+    pub fn format(&self, addr: &dyn Fn(usize) -> String) -> String {
+        use Instruction::*;
+        match *self {
+            Cls => "CLS".into(),
+            Ret => "RET".into(),
+            Scd(n) => format!("SCD {}", n),
+            Scu(n) => format!("SCU {}", n),
+            Scr => "SCR".into(),
+            Scl => "SCL".into(),
+            Exit => "EXIT".into(),
+            Low => "LOW".into(),
+            High => "HIGH".into(),
+            Sys(nnn) => format!("SYS {}", addr(nnn)),
+            Jp(nnn) => format!("JP {}", addr(nnn)),
+            Call(nnn) => format!("CALL {}", addr(nnn)),
+            SeVxByte(x, kk) => format!("SE V{:X}, 0x{:02x}", x, kk),
+            SneVxByte(x, kk) => format!("SNE V{:X}, 0x{:02x}", x, kk),
+            SeVxVy(x, y) => format!("SE V{:X}, V{:X}", x, y),
+            LdMemIVxVy(x, y) => format!("LD [I], V{:X}-V{:X}", x, y),
+            LdVxVyMemI(x, y) => format!("LD V{:X}-V{:X}, [I]", x, y),
+            LdVxByte(x, kk) => format!("LD V{:X}, 0x{:02x}", x, kk),
+            AddVxByte(x, kk) => format!("ADD V{:X}, 0x{:02x}", x, kk),
+            LdVxVy(x, y) => format!("LD V{:X}, V{:X}", x, y),
+            OrVxVy(x, y) => format!("OR V{:X}, V{:X}", x, y),
+            AndVxVy(x, y) => format!("AND V{:X}, V{:X}", x, y),
+            XorVxVy(x, y) => format!("XOR V{:X}, V{:X}", x, y),
+            AddVxVy(x, y) => format!("ADD V{:X}, V{:X}", x, y),
+            SubVxVy(x, y) => format!("SUB V{:X}, V{:X}", x, y),
+            ShrVxVy(x, y) => format!("SHR V{:X}, V{:X}", x, y),
+            SubnVxVy(x, y) => format!("SUBN V{:X}, V{:X}", x, y),
+            ShlVxVy(x, y) => format!("SHL V{:X}, V{:X}", x, y),
+            SneVxVy(x, y) => format!("SNE V{:X}, V{:X}", x, y),
+            LdIAddr(nnn) => format!("LD I, {}", addr(nnn)),
+            JpV0Addr(nnn) => format!("JP V0, {}", addr(nnn)),
+            RndVxByte(x, kk) => format!("RND V{:X}, 0x{:02x}", x, kk),
+            DrwVxVyNibble(x, y, n) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+            SkpVx(x) => format!("SKP V{:X}", x),
+            SknpVx(x) => format!("SKNP V{:X}", x),
+            LdILong(nnnn) => format!("LD I, long {}", addr(nnnn)),
+            Plane(n) => format!("PLANE {}", n),
+            Audio => "AUDIO".into(),
+            LdVxDt(x) => format!("LD V{:X}, DT", x),
+            LdVxK(x) => format!("LD V{:X}, K", x),
+            LdDtVx(x) => format!("LD DT, V{:X}", x),
+            LdStVx(x) => format!("LD ST, V{:X}", x),
+            AddIVx(x) => format!("ADD I, V{:X}", x),
+            LdFVx(x) => format!("LD F, V{:X}", x),
+            LdHfVx(x) => format!("LD HF, V{:X}", x),
+            LdBVx(x) => format!("LD B, V{:X}", x),
+            PitchVx(x) => format!("PITCH V{:X}", x),
+            LdMemIVx(x) => format!("LD [I], V{:X}", x),
+            LdVxMemI(x) => format!("LD V{:X}, [I]", x),
+            LdRVx(x) => format!("LD R, V{:X}", x),
+            LdVxR(x) => format!("LD V{:X}, R", x),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.format(&|addr| format!("0x{:03x}", addr)))
+    }
+}
+
+/// Listing of `rom` loaded at `base` that follows the control flow from `base`: jumps, calls,
+/// skips and returns. Bytes never reached are data, shown one per line as sprite rows, and
+/// `LD I` targets are labelled as such. `JP V0` is flagged since its targets can't be followed.
+pub fn listing(rom: &[u8], base: usize) -> String {
+    let mut code = BTreeMap::new();
+    let mut data = BTreeSet::new();
+    let mut pending = vec![base];
+    while let Some(addr) = pending.pop() {
+        if code.contains_key(&addr) || !(base..base + rom.len()).contains(&addr) {
+            continue;
+        }
+        let instruction = match Instruction::decode(&rom[addr - base..]) {
+            Some(instruction) => instruction,
+            // Ran into data, the path is most likely never taken
+            None => continue,
+        };
+        code.insert(addr, instruction);
+
+        let next = addr + instruction.size();
+        match instruction {
+            Instruction::Jp(target) => pending.push(target),
+            Instruction::Call(target) => pending.extend([target, next]),
+            Instruction::Ret | Instruction::Exit | Instruction::JpV0Addr(_) => {}
+            Instruction::LdIAddr(target) | Instruction::LdILong(target) => {
+                data.insert(target);
+                pending.push(next);
+            }
+            _ if instruction.is_skip() => {
+                // The skipped instruction may be the 4 byte F000 NNNN
+                let skipped = rom
+                    .get(next - base..)
+                    .and_then(Instruction::decode)
+                    .map_or(2, |skipped| skipped.size());
+                pending.extend([next, next + skipped]);
+            }
+            _ => pending.push(next),
+        }
+    }
+
+    let mut decoded = vec![];
+    let mut addr = base;
+    while addr < base + rom.len() {
+        match code.get(&addr) {
+            Some(instruction) => {
+                decoded.push((addr, Some(*instruction)));
+                addr += instruction.size();
+            }
+            None => {
+                decoded.push((addr, None));
+                addr += 1;
+            }
+        }
+    }
+    render(rom, base, &decoded, &data)
+}
+
+/// Listing of `rom` loaded at `base` that decodes everything it can as instructions, data
+/// included
+pub fn linear_listing(rom: &[u8], base: usize) -> String {
+    let mut decoded = vec![];
+    let mut offset = 0;
+    while offset < rom.len() {
+        let instruction = Instruction::decode(&rom[offset..]);
+        decoded.push((base + offset, instruction));
+        offset += instruction.map_or(1, |i| i.size());
+    }
+    render(rom, base, &decoded, &BTreeSet::new())
+}
+
+// One line per instruction or data byte (None), with a label before every address an
+// instruction refers to
+fn render(
+    rom: &[u8],
+    base: usize,
+    decoded: &[(usize, Option<Instruction>)],
+    data: &BTreeSet<usize>,
+) -> String {
+    // Targets in the middle of an instruction stay numeric
+    let starts: BTreeSet<usize> = decoded.iter().map(|(addr, _)| *addr).collect();
+    let labels: BTreeSet<usize> = decoded
+        .iter()
+        .filter_map(|(_, instruction)| instruction.and_then(|i| i.target()))
+        .filter(|target| starts.contains(target))
+        .collect();
+    let name = |addr: usize| {
+        if labels.contains(&addr) {
+            label(addr)
+        } else {
+            format!("0x{:03x}", addr)
+        }
+    };
+
+    let mut out = String::new();
+    for (addr, instruction) in decoded {
+        let addr = *addr;
+        if labels.contains(&addr) {
+            if data.contains(&addr) {
+                out += &format!("{}: ; data\n", label(addr));
+            } else {
+                out += &format!("{}:\n", label(addr));
+            }
+        }
+        let len = instruction.map_or(1, |i| i.size());
+        let bytes = &rom[addr - base..addr - base + len];
+        let text = match instruction {
+            Some(jump @ Instruction::JpV0Addr(_)) => {
+                format!("{}  ; indirect jump", jump.format(&name))
+            }
+            Some(instruction) => instruction.format(&name),
+            None => {
+                let row: String = (0..8)
+                    .rev()
+                    .map(|bit| if bytes[0] >> bit & 1 == 1 { '#' } else { '.' })
+                    .collect();
+                format!("db 0b{:08b}  ; {}", bytes[0], row)
+            }
+        };
+        out += &format!("{}\n", line(addr, bytes, &text));
+    }
+    out
+}
+
+pub fn label(addr: usize) -> String {
+    format!("L{:03X}", addr)
+}
+
+// "0x0200  a2 b4        LD I, L2B4"
+fn line(addr: usize, bytes: &[u8], text: &str) -> String {
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("0x{:04x}  {:<12}  {}", addr, hex.join(" "), text)
+}
@@ -0,0 +1,224 @@
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info};
+
+use crate::chip8::{Chip8, Chip8Exception, State};
+use crate::debugger::{DebugClient, Stop};
+use crate::movie::MovieMode;
+use crate::rewind::Rewind;
+
+/// Emulator controls, as opposed to the CHIP-8 keypad
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Command {
+    Quit,
+    Reset,
+    TogglePause,
+    Step,
+    SpeedUp,
+    SpeedDown,
+    ResetSpeed,
+    SaveState(u8),
+    LoadState(u8),
+    /// Step back through the rewind history, one frame per frame, until `StopRewind`
+    Rewind,
+    StopRewind,
+}
+
+/// Display, audio and input backend driven by `run`
+pub trait Frontend {
+    /// Show the machine's framebuffer, `machine.video_memory_tainted` tells if it changed
+    fn present(&mut self, machine: &Chip8);
+
+    /// Turn the buzzer on or off, `pattern` is the XO-CHIP audio pattern played at `pattern_rate` bits per second
+    fn set_buzzer(&mut self, active: bool, pattern: Option<[u8; 16]>, pattern_rate: f32);
+
+    /// Current state of the 16 keys of the hexadecimal keypad
+    fn poll_keypad(&mut self) -> [bool; 16];
+
+    /// Controls requested since the last poll, polled before the keypad
+    fn poll_commands(&mut self) -> Vec<Command>;
+
+    fn report_fault(&mut self, exception: &Chip8Exception);
+
+    fn clear_fault(&mut self) {}
+
+    /// Keep a save state in the numbered `slot`
+    fn write_state(&mut self, _slot: u8, _state: &[u8]) {}
+
+    /// The save state last written to `slot`, if any
+    fn read_state(&mut self, _slot: u8) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Whether `present` blocks until the next vertical blank, pacing the frames by itself
+    fn vsync(&self) -> bool {
+        false
+    }
+}
+
+pub const FRAME_RATE: f64 = 60.0;
+
+/// Run the machine one 60 Hz frame at a time until the frontend asks to quit, presenting once
+/// per frame. Speed commands change `machine.instructions_per_frame`, timers are unaffected.
+/// Every running frame is recorded in `history` for rewinding, and its keypad goes through
+/// `movie`. Controls that would break a movie's determinism are ignored while one is active.
+/// With a `debug` client the machine only runs when its debugger lets it, and frames it stops
+/// are neither recorded nor rewound. `boot` provides a fresh machine whenever a reset is
+/// requested.
+pub fn run<F, B>(
+    frontend: &mut F,
+    machine: &mut Chip8,
+    history: &mut Rewind,
+    movie: &mut MovieMode,
+    mut debug: Option<&mut dyn DebugClient>,
+    boot: B,
+) where
+    F: Frontend,
+    B: Fn() -> Result<Chip8, Chip8Exception>,
+{
+    let default_instructions_per_frame = machine.instructions_per_frame;
+    let frame_period = Duration::from_secs_f64(1.0 / FRAME_RATE);
+    let mut next_frame = Instant::now() + frame_period;
+    let mut rewinding = false;
+
+    'event_loop: loop {
+        let looping_time = Instant::now();
+        let commands = frontend.poll_commands();
+        let mut keypad = frontend.poll_keypad();
+
+        for command in commands {
+            if movie.is_active()
+                && matches!(
+                    command,
+                    Command::Step
+                        | Command::SpeedUp
+                        | Command::SpeedDown
+                        | Command::ResetSpeed
+                        | Command::LoadState(_)
+                        | Command::Rewind
+                )
+            {
+                info!(
+                    "{:?} is disabled while a movie is recording or playing",
+                    command
+                );
+                continue;
+            }
+
+            match command {
+                Command::Quit => break 'event_loop,
+                Command::Reset => match boot() {
+                    Ok(fresh_machine) => {
+                        // SuperChip-8 RPL flags and the speed survive resets. Movies start
+                        // with cleared flags, like they are recorded and played back.
+                        let (rpl, speed) = (machine.rpl, machine.instructions_per_frame);
+                        *machine = fresh_machine;
+                        if !movie.is_active() {
+                            machine.rpl = rpl;
+                        }
+                        machine.instructions_per_frame = speed;
+                        movie.restart();
+                        frontend.clear_fault();
+                        frontend.present(machine);
+                    }
+                    Err(exception) => frontend.report_fault(&exception),
+                },
+                Command::TogglePause => machine.int(),
+                Command::Step => {
+                    if let Err(exception) = machine.next(&keypad) {
+                        frontend.report_fault(&exception);
+                    }
+                }
+                Command::SpeedUp => machine.instructions_per_frame *= 2,
+                Command::SpeedDown => {
+                    machine.instructions_per_frame = (machine.instructions_per_frame / 2).max(1)
+                }
+                Command::ResetSpeed => {
+                    machine.instructions_per_frame = default_instructions_per_frame
+                }
+                Command::SaveState(slot) => frontend.write_state(slot, &machine.save_state()),
+                Command::LoadState(slot) => {
+                    if let Some(state) = frontend.read_state(slot) {
+                        match machine.load_state(&state) {
+                            Ok(()) => {
+                                frontend.clear_fault();
+                                frontend.present(machine);
+                            }
+                            Err(e) => error!("Unable to load state {}: {}", slot, e),
+                        }
+                    }
+                }
+                Command::Rewind => rewinding = true,
+                Command::StopRewind => rewinding = false,
+            }
+        }
+
+        if let Some(client) = debug.as_mut() {
+            client.poll(machine);
+        }
+
+        if rewinding {
+            // Past the oldest frame the machine stays there
+            if let Some(state) = history.pop() {
+                match machine.load_state(&state) {
+                    Ok(()) => frontend.clear_fault(),
+                    Err(e) => error!("Unable to rewind: {}", e),
+                }
+            }
+        } else {
+            let debugger_paused = debug
+                .as_mut()
+                .is_some_and(|client| client.debugger().paused());
+            if machine.state == State::Running && !debugger_paused {
+                history.push(machine.save_state());
+                keypad = movie.next_frame(keypad);
+            }
+            let result = match debug.as_mut() {
+                Some(client) => match client.debugger().run_frame(machine, &keypad) {
+                    Ok(stop) => {
+                        if let Some(stop) = stop {
+                            client.stopped(machine, &stop);
+                        }
+                        Ok(())
+                    }
+                    Err(exception) => {
+                        client.stopped(machine, &Stop::Fault(exception.to_string()));
+                        Err(exception)
+                    }
+                },
+                None => machine.run_frame(&keypad),
+            };
+            if let Err(exception) = result {
+                frontend.report_fault(&exception);
+            }
+        }
+
+        frontend.set_buzzer(
+            machine.st != 0 && !rewinding,
+            machine.audio_pattern,
+            machine.audio_sample_rate(),
+        );
+        frontend.present(machine);
+
+        if frontend.vsync() {
+            continue;
+        }
+
+        let elapsed_time = looping_time.elapsed();
+        let now = Instant::now();
+        if next_frame > now {
+            let sleep_required = next_frame - now;
+            spin_sleep::sleep(sleep_required);
+            debug!(
+                "Frame time: {}us, Sleep Required: {}us, Error: {}us",
+                elapsed_time.as_micros(),
+                sleep_required.as_micros(),
+                Instant::now().duration_since(next_frame).as_micros(),
+            );
+            next_frame += frame_period;
+        } else {
+            // Running behind, don't try to catch up
+            next_frame = now + frame_period;
+        }
+    }
+}
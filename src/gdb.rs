@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io;
+
+use crate::chip8::Chip8;
+use crate::connection::Connection;
+use crate::debugger::{Access, Breakpoint, DebugClient, Debugger, Location, Stop};
+
+// Register numbers of the target description: V0-VF, then I, PC, SP, DT and ST
+const REGISTER_COUNT: usize = 21;
+const PACKET_SIZE: usize = 0x4000;
+
+/// GDB remote serial protocol server on a local TCP port. The machine runs freely until a
+/// debugger attaches, which stops it, and again once it detaches. I and PC are 16 bit registers,
+/// the rest 8 bit, all little endian as described by `target.xml`.
+pub struct GdbServer {
+    debugger: Debugger,
+    connection: Connection,
+    no_ack: bool,
+    // GDB sent a continue or step and waits for the stop reply
+    resumed: bool,
+    // Breakpoint ids by Z packet type and address
+    points: HashMap<(u8, usize), usize>,
+}
+
+impl GdbServer {
+    pub fn new(port: u16) -> io::Result<Self> {
+        Ok(Self {
+            debugger: Debugger::new(),
+            connection: Connection::listen(port, "GDB")?,
+            no_ack: false,
+            resumed: false,
+            points: HashMap::new(),
+        })
+    }
+
+    fn accept(&mut self, machine: &Chip8) {
+        if self.connection.accept() {
+            self.no_ack = false;
+            self.resumed = false;
+            self.debugger.pause();
+        } else if self.debugger.paused() {
+            self.debugger.resume(machine);
+        }
+    }
+
+    // Forget the client and let the machine run on its own
+    fn detach(&mut self, machine: &Chip8) {
+        self.connection.close();
+        for (_, id) in self.points.drain() {
+            self.debugger.remove_breakpoint(id);
+        }
+        self.debugger.resume(machine);
+    }
+
+    // Next packet payload in the input, acknowledging it. Interrupts come out as "\x03".
+    fn next_packet(&mut self) -> Option<String> {
+        loop {
+            match *self.connection.input.first()? {
+                b'$' => break,
+                0x03 => {
+                    self.connection.input.remove(0);
+                    return Some("\x03".to_string());
+                }
+                // Acks and noise between packets
+                _ => {
+                    self.connection.input.remove(0);
+                }
+            }
+        }
+        let end = self
+            .connection
+            .input
+            .iter()
+            .position(|byte| *byte == b'#')?;
+        if self.connection.input.len() < end + 3 {
+            return None;
+        }
+        let packet: Vec<u8> = self.connection.input.drain(..end + 3).collect();
+        let payload = &packet[1..end];
+        let checksum = std::str::from_utf8(&packet[end + 1..])
+            .ok()
+            .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+        if !self.no_ack {
+            let valid = checksum == Some(checksum_of(payload));
+            self.connection.write(if valid { b"+" } else { b"-" });
+            if !valid {
+                return self.next_packet();
+            }
+        }
+        Some(String::from_utf8_lossy(payload).into_owned())
+    }
+
+    fn send(&mut self, payload: &str) {
+        let packet = format!("${}#{:02x}", payload, checksum_of(payload.as_bytes()));
+        self.connection.write(packet.as_bytes());
+    }
+
+    // Reply to `packet`, None when the reply is the stop reply sent once the machine stops
+    fn handle(&mut self, machine: &mut Chip8, packet: &str) -> Option<String> {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "\x03" => {
+                self.debugger.pause();
+                return None;
+            }
+            "?" => "S05".to_string(),
+            "g" => {
+                let mut registers = String::new();
+                for register in 0..REGISTER_COUNT {
+                    registers += &read_register(machine, register);
+                }
+                registers
+            }
+            "G" => {
+                let mut rest = args;
+                for register in 0..REGISTER_COUNT {
+                    let len = register_size(register) * 2;
+                    if rest.len() < len {
+                        return Some("E01".to_string());
+                    }
+                    let (value, tail) = rest.split_at(len);
+                    if !write_register(machine, register, value) {
+                        return Some("E01".to_string());
+                    }
+                    rest = tail;
+                }
+                "OK".to_string()
+            }
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(register) if register < REGISTER_COUNT => read_register(machine, register),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let written = args.split_once('=').is_some_and(|(register, value)| {
+                    usize::from_str_radix(register, 16)
+                        .is_ok_and(|register| write_register(machine, register, value))
+                });
+                if written { "OK" } else { "E01" }.to_string()
+            }
+            "m" => match parse_range(args) {
+                Some((addr, len)) if addr < machine.address_space() => {
+                    let end = match addr.checked_add(len) {
+                        Some(end) => end.min(machine.address_space()),
+                        None => return Some("E01".to_string()),
+                    };
+                    machine.memory[addr..end]
+                        .iter()
+                        .fold(String::new(), |mut out, byte| {
+                            let _ = write!(out, "{:02x}", byte);
+                            out
+                        })
+                }
+                _ => "E01".to_string(),
+            },
+            "M" => {
+                let written = args.split_once(':').is_some_and(|(range, data)| {
+                    match (parse_range(range), decode_hex(data)) {
+                        (Some((addr, len)), Some(bytes))
+                            if bytes.len() == len
+                                && addr
+                                    .checked_add(len)
+                                    .is_some_and(|end| end <= machine.address_space()) =>
+                        {
+                            machine.memory[addr..addr + len].copy_from_slice(&bytes);
+                            true
+                        }
+                        _ => false,
+                    }
+                });
+                if written { "OK" } else { "E01" }.to_string()
+            }
+            "c" | "s" => {
+                // Resuming somewhere else first
+                if let Ok(addr) = usize::from_str_radix(args, 16) {
+                    machine.pc = addr;
+                }
+                if command == "c" {
+                    self.debugger.resume(machine);
+                } else {
+                    self.debugger.step(machine);
+                }
+                self.resumed = true;
+                return None;
+            }
+            "Z" | "z" => self.handle_point(command == "Z", args),
+            "D" => {
+                self.send("OK");
+                self.detach(machine);
+                return None;
+            }
+            "k" => {
+                self.detach(machine);
+                return None;
+            }
+            "H" => "OK".to_string(),
+            "T" => "OK".to_string(),
+            "q" | "Q" => self.handle_query(packet),
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn handle_query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            format!(
+                "PacketSize={:x};qXfer:features:read+;swbreak+;QStartNoAckMode+",
+                PACKET_SIZE
+            )
+        } else if packet == "QStartNoAckMode" {
+            // The OK itself is still acknowledged
+            self.send("OK");
+            self.no_ack = true;
+            String::new()
+        } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let xml = target_xml();
+            match parse_range(range) {
+                Some((offset, len)) if offset <= xml.len() => {
+                    let end = offset.saturating_add(len).min(xml.len());
+                    let more = if end < xml.len() { 'm' } else { 'l' };
+                    format!("{}{}", more, &xml[offset..end])
+                }
+                _ => "E01".to_string(),
+            }
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_string()
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    // Z0/Z1 breakpoints, Z2 write, Z3 read and Z4 access watchpoints
+    fn handle_point(&mut self, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let kind = fields.next().and_then(|kind| kind.parse::<u8>().ok());
+        let addr = fields
+            .next()
+            .and_then(|addr| usize::from_str_radix(addr, 16).ok());
+        let len = fields
+            .next()
+            .and_then(|len| usize::from_str_radix(len, 16).ok());
+        let (kind, addr, len) = match (kind, addr, len) {
+            (Some(kind), Some(addr), Some(len)) => (kind, addr, len),
+            _ => return "E01".to_string(),
+        };
+
+        let breakpoint = match kind {
+            0 | 1 => Breakpoint::Pc(addr),
+            2 => Breakpoint::Watch(Location::Memory(addr, len), Access::Write),
+            3 => Breakpoint::Watch(Location::Memory(addr, len), Access::Read),
+            4 => Breakpoint::Watch(Location::Memory(addr, len), Access::ReadWrite),
+            _ => return String::new(),
+        };
+        if insert {
+            if !self.points.contains_key(&(kind, addr)) {
+                let id = self.debugger.add_breakpoint(breakpoint);
+                self.points.insert((kind, addr), id);
+            }
+        } else if let Some(id) = self.points.remove(&(kind, addr)) {
+            self.debugger.remove_breakpoint(id);
+        }
+        "OK".to_string()
+    }
+}
+
+impl DebugClient for GdbServer {
+    fn debugger(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    fn poll(&mut self, machine: &mut Chip8) {
+        if !self.connection.is_connected() {
+            self.accept(machine);
+            return;
+        }
+        if !self.connection.receive() {
+            self.detach(machine);
+            return;
+        }
+        while let Some(packet) = self.next_packet() {
+            if let Some(reply) = self.handle(machine, &packet) {
+                self.send(&reply);
+            }
+            if !self.connection.is_connected() {
+                break;
+            }
+        }
+    }
+
+    fn stopped(&mut self, _machine: &Chip8, stop: &Stop) {
+        if !self.resumed {
+            return;
+        }
+        self.resumed = false;
+        let reply = match stop {
+            Stop::Step => "S05".to_string(),
+            Stop::Pause => "S02".to_string(),
+            Stop::Fault(_) => "S0b".to_string(),
+            Stop::Breakpoint(id) => match self.debugger.breakpoints.get(id) {
+                Some(Breakpoint::Watch(Location::Memory(addr, _), access)) => {
+                    let reason = match access {
+                        Access::Write => "watch",
+                        Access::Read => "rwatch",
+                        Access::ReadWrite => "awatch",
+                    };
+                    format!("T05{}:{:x};", reason, addr)
+                }
+                _ => "T05swbreak:;".to_string(),
+            },
+        };
+        self.send(&reply);
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum: u8, byte| sum.wrapping_add(*byte))
+}
+
+fn register_size(register: usize) -> usize {
+    match register {
+        16 | 17 => 2,
+        _ => 1,
+    }
+}
+
+fn read_register(machine: &Chip8, register: usize) -> String {
+    match register {
+        0..=15 => format!("{:02x}", machine.v[register]),
+        16 => format!("{:02x}{:02x}", machine.i & 0xFF, (machine.i >> 8) & 0xFF),
+        17 => format!("{:02x}{:02x}", machine.pc & 0xFF, (machine.pc >> 8) & 0xFF),
+        18 => format!("{:02x}", machine.sp),
+        19 => format!("{:02x}", machine.dt),
+        _ => format!("{:02x}", machine.st),
+    }
+}
+
+fn write_register(machine: &mut Chip8, register: usize, value: &str) -> bool {
+    let bytes = match decode_hex(value) {
+        Some(bytes) if bytes.len() == register_size(register) => bytes,
+        _ => return false,
+    };
+    let word = bytes
+        .iter()
+        .rev()
+        .fold(0, |word, byte| word << 8 | *byte as usize);
+    match register {
+        0..=15 => machine.v[register] = bytes[0],
+        16 => machine.i = word,
+        17 => machine.pc = word,
+        18 if word <= machine.stack.len() => machine.sp = word,
+        19 => machine.dt = bytes[0],
+        20 => machine.st = bytes[0],
+        _ => return false,
+    }
+    true
+}
+
+// "addr,len" in hex
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    let (addr, len) = range.split_once(',')?;
+    Some((
+        usize::from_str_radix(addr, 16).ok()?,
+        usize::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn target_xml() -> String {
+    let mut registers = String::new();
+    for v in 0..16 {
+        let _ = writeln!(
+            registers,
+            r#"    <reg name="v{:x}" bitsize="8" type="uint8"/>"#,
+            v
+        );
+    }
+    format!(
+        r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rust-chip8.cpu">
+{}    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="dt" bitsize="8" type="uint8"/>
+    <reg name="st" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#,
+        registers
+    )
+}
@@ -0,0 +1,22 @@
+extern crate log;
+
+pub mod asm;
+pub mod boot;
+pub mod chip8;
+mod connection;
+pub mod console;
+pub mod dap;
+pub mod database;
+pub mod debugger;
+pub mod disasm;
+pub mod frontend;
+pub mod gdb;
+pub mod movie;
+pub mod quirks;
+pub mod random;
+pub mod rewind;
+pub mod savestate;
+pub mod sourcemap;
+pub mod timing;
+
+pub use crate::chip8::{Chip8, Chip8Exception, State};
@@ -1,208 +1,514 @@
 use sdl2::render::{Canvas, RenderTarget};
 
+use sdl2::audio::AudioDevice;
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Mod};
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
-use std::fs::File;
-use std::io::Read;
-use std::time::{Duration, Instant};
+use sdl2::video::Window;
+use sdl2::EventPump;
+use std::fs;
 extern crate env_logger;
 extern crate log;
-use crate::chip8::Chip8;
-use log::debug;
+use log::{error, info};
+use rust_chip8::boot::{boot, BootOptions};
+use rust_chip8::chip8::MemoryPolicy;
+use rust_chip8::console::Console;
+use rust_chip8::dap::DapServer;
+use rust_chip8::database;
+use rust_chip8::debugger::DebugClient;
+use rust_chip8::frontend::{self, Command, Frontend};
+use rust_chip8::gdb::GdbServer;
+use rust_chip8::movie::{Movie, MovieMode};
+use rust_chip8::random::RngAlgorithm;
+use rust_chip8::rewind::Rewind;
+use rust_chip8::sourcemap::SourceMap;
+use rust_chip8::timing::Timing;
+use rust_chip8::{Chip8, Chip8Exception};
 use std::env;
-extern crate spin_sleep;
-const CELL_PIXEL_SIDE: u32 = 20;
-const X_SIZE: u32 = CELL_PIXEL_SIDE * 64;
-const Y_SIZE: u32 = CELL_PIXEL_SIDE * 32;
+const X_SIZE: u32 = 1280;
+const Y_SIZE: u32 = 640;
+
+// Indexed by the bitplanes set on each pixel
+const PALETTE: [(u8, u8, u8); 16] = [
+    (0x00, 0x00, 0x00),
+    (0xFF, 0xFF, 0xFF),
+    (0xFF, 0x66, 0x00),
+    (0x99, 0x99, 0x99),
+    (0x00, 0x99, 0xFF),
+    (0x00, 0x66, 0x99),
+    (0xFF, 0x33, 0x66),
+    (0xCC, 0x00, 0x33),
+    (0x33, 0xCC, 0x33),
+    (0x00, 0x66, 0x00),
+    (0xFF, 0xFF, 0x66),
+    (0x99, 0x99, 0x00),
+    (0x99, 0x66, 0xFF),
+    (0x66, 0x33, 0x99),
+    (0x66, 0xFF, 0xFF),
+    (0x33, 0x99, 0x99),
+];
 
 /*
 let color_on: Color = Color::RGB(255, 255, 255);
 let color_off: Color = Color::RGB(0, 0, 0);
 */
 
-pub mod chip8;
+struct Options {
+    rom_path: String,
+    boot: BootOptions,
+    rewind_seconds: usize,
+    record_path: Option<String>,
+    play_path: Option<String>,
+    vsync: bool,
+    debug: bool,
+    gdb_port: Option<u16>,
+    dap_port: Option<u16>,
+    source_map_path: Option<String>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut rom_path = None;
+    let mut boot = BootOptions::default();
+    let mut rewind_seconds = 10;
+    let mut record_path = None;
+    let mut play_path = None;
+    let mut vsync = false;
+    let mut debug = false;
+    let mut gdb_port = None;
+    let mut dap_port = None;
+    let mut source_map_path = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--wrap-memory" => boot.memory_policy = MemoryPolicy::Wrap,
+            "--platform" => {
+                let name = args.next().ok_or("--platform requires a value")?;
+                boot.platform = Some(name.parse()?);
+            }
+            "--ipf" => {
+                let ipf = args.next().ok_or("--ipf requires a value")?;
+                boot.instructions_per_frame = Some(
+                    ipf.parse()
+                        .ok()
+                        .filter(|ipf| *ipf > 0)
+                        .ok_or(format!("Invalid instructions per frame {}", ipf))?,
+                );
+            }
+            "--vip-timing" => boot.timing = Timing::CosmacVip,
+            "--vsync" => vsync = true,
+            "--debug" => debug = true,
+            "--gdb" => {
+                let port = args.next().ok_or("--gdb requires a port")?;
+                gdb_port = Some(port.parse().map_err(|_| format!("Invalid port {}", port))?);
+            }
+            "--dap" => {
+                let port = args.next().ok_or("--dap requires a port")?;
+                dap_port = Some(port.parse().map_err(|_| format!("Invalid port {}", port))?);
+            }
+            "--source-map" => {
+                source_map_path = Some(args.next().ok_or("--source-map requires a file")?)
+            }
+            "--seed" => {
+                let seed = args.next().ok_or("--seed requires a value")?;
+                boot.rng_seed = Some(seed.parse().map_err(|_| format!("Invalid seed {}", seed))?);
+            }
+            "--vip-rng" => boot.rng_algorithm = RngAlgorithm::CosmacVip,
+            "--rewind" => {
+                let seconds = args.next().ok_or("--rewind requires a value")?;
+                rewind_seconds = seconds
+                    .parse()
+                    .map_err(|_| format!("Invalid rewind length {}", seconds))?;
+            }
+            "--key-press-only" => boot.key_press_only = true,
+            "--record" => record_path = Some(args.next().ok_or("--record requires a file")?),
+            "--play" => play_path = Some(args.next().ok_or("--play requires a file")?),
+            flag if flag.starts_with("--") => return Err(format!("Unknown option {}", flag)),
+            _ => rom_path = Some(arg),
+        }
+    }
+
+    if record_path.is_some() && play_path.is_some() {
+        return Err("--record and --play can't be combined".to_string());
+    }
+    let debuggers = debug as usize + gdb_port.is_some() as usize + dap_port.is_some() as usize;
+    if debuggers > 1 {
+        return Err("Only one of --debug, --gdb and --dap can be used".to_string());
+    }
+    // Stepping would run frames shorter than the ones recorded
+    if debuggers > 0 && (record_path.is_some() || play_path.is_some()) {
+        return Err("Debugging can't be combined with --record or --play".to_string());
+    }
+
+    Ok(Options {
+        rom_path: rom_path.ok_or("ROM missing!")?,
+        boot,
+        rewind_seconds,
+        record_path,
+        play_path,
+        vsync,
+        debug,
+        gdb_port,
+        dap_port,
+        source_map_path,
+    })
+}
 
 fn main() {
     env_logger::init();
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        println!("ROM missing!");
-        return;
+    let mut options = match parse_args() {
+        Ok(options) => options,
+        Err(message) => {
+            println!("{}", message);
+            return;
+        }
+    };
+
+    let rom = fs::read(&options.rom_path).unwrap();
+    let rom_info = database::lookup(&rom);
+    let mut palette = PALETTE;
+
+    match &rom_info {
+        Some(info) => {
+            println!("{}", info.title);
+            for (action, key) in &info.keys {
+                println!("  {}: {:X}", action, key);
+            }
+            for (i, color) in info.colors.iter().take(palette.len()).enumerate() {
+                palette[i] = *color;
+            }
+        }
+        None => info!(
+            "ROM {} not found in the database, using defaults",
+            database::hash(&rom)
+        ),
     }
 
-    let sdl_context = sdl2::init().unwrap();
+    let window_title = match &rom_info {
+        Some(info) => format!("Chip-8 - {}", info.title),
+        None => "Chip-8".to_string(),
+    };
+
+    let playback = match &options.play_path {
+        Some(path) => match Movie::load(path, &rom) {
+            Ok(movie) => Some(movie),
+            Err(message) => {
+                println!("{}", message);
+                return;
+            }
+        },
+        None => None,
+    };
+    let recording_seed = match options.record_path {
+        Some(_) => Some(options.boot.recording_seed()),
+        None => None,
+    };
 
-    let audio_subsystem = sdl_context.audio().unwrap();
+    let mut machine = match boot(&options.boot, rom_info.as_ref(), &rom, playback.as_ref()) {
+        Ok(machine) => machine,
+        Err(exception) => {
+            println!("Unable to load ROM: {}", exception);
+            return;
+        }
+    };
 
-    let desired_spec = AudioSpecDesired {
-        freq: Some(44100),
-        channels: Some(1),
-        samples: Some(512),
+    let mut movie = match (&playback, recording_seed) {
+        (Some(movie), _) => MovieMode::Playing {
+            movie: movie.clone(),
+            frame: 0,
+        },
+        (None, Some(seed)) => MovieMode::Recording(Movie::new(&machine, &rom, seed)),
+        _ => MovieMode::Off,
     };
 
-    let buzzer = audio_subsystem
-        .open_playback(None, &desired_spec, |spec| {
-            // initialize the audio callback
-            SquareWave {
-                phase_inc: 440.0 / spec.freq as f32,
-                phase: 0.0,
-                volume: 0.25,
+    // RPL flags would make movies depend on the file, they are left alone while one is active
+    let rpl_path = format!("{}.rpl", options.rom_path);
+    let use_rpl_flags = !movie.is_active();
+    if use_rpl_flags {
+        load_rpl_flags(&rpl_path, &mut machine);
+    }
+
+    let state_path = format!("{}.state", options.rom_path);
+    let mut frontend = SdlFrontend::new(window_title, palette, state_path, options.vsync);
+    let mut history = Rewind::new(options.rewind_seconds * frontend::FRAME_RATE as usize);
+    let source_map = match options.source_map_path.as_deref().map(SourceMap::load) {
+        Some(Ok(source_map)) => Some(source_map),
+        Some(Err(message)) => {
+            println!("{}", message);
+            return;
+        }
+        None => None,
+    };
+    let mut debug_client: Option<Box<dyn DebugClient>> = match (options.gdb_port, options.dap_port)
+    {
+        (Some(port), _) => match GdbServer::new(port) {
+            Ok(server) => Some(Box::new(server)),
+            Err(e) => {
+                println!("Unable to listen for GDB on port {}: {}", port, e);
+                return;
             }
-        })
-        .unwrap();
+        },
+        (_, Some(port)) => match DapServer::new(port, source_map) {
+            Ok(server) => Some(Box::new(server)),
+            Err(e) => {
+                println!("Unable to listen for DAP clients on port {}: {}", port, e);
+                return;
+            }
+        },
+        _ if options.debug => Some(Box::new(Console::new())),
+        _ => None,
+    };
+    frontend::run(
+        &mut frontend,
+        &mut machine,
+        &mut history,
+        &mut movie,
+        debug_client
+            .as_mut()
+            .map(|client| client.as_mut() as &mut dyn DebugClient),
+        || boot(&options.boot, rom_info.as_ref(), &rom, playback.as_ref()),
+    );
+
+    if let (Some(path), MovieMode::Recording(movie)) = (&options.record_path, &movie) {
+        match fs::write(path, movie.to_bytes()) {
+            Ok(()) => info!("Recorded {} frames to {}", movie.frames.len(), path),
+            Err(e) => error!("Unable to save movie to {}: {}", path, e),
+        }
+    }
+    if use_rpl_flags {
+        save_rpl_flags(&rpl_path, &machine);
+    }
+}
 
-    let video_subsystem = sdl_context.video().unwrap();
-    let window = video_subsystem
-        .window("Chip-8", X_SIZE, Y_SIZE)
-        .position_centered()
-        .opengl()
-        .build()
-        .unwrap();
+// SuperChip-8 RPL user flags survive between runs, like they did on the HP-48
+fn load_rpl_flags(path: &str, machine: &mut Chip8) {
+    if let Ok(flags) = fs::read(path) {
+        let len = flags.len().min(machine.rpl.len());
+        machine.rpl[..len].copy_from_slice(&flags[..len]);
+    }
+}
 
-    let mut canvas = window.into_canvas().build().unwrap();
+fn save_rpl_flags(path: &str, machine: &Chip8) {
+    if machine.rpl.iter().all(|flag| *flag == 0) {
+        return;
+    }
+
+    if let Err(e) = fs::write(path, machine.rpl) {
+        error!("Unable to save RPL flags to {}: {}", path, e);
+    }
+}
+
+struct SdlFrontend {
+    canvas: Canvas<Window>,
+    event_pump: EventPump,
+    buzzer: AudioDevice<Buzzer>,
+    keyboard: [bool; 16],
+    palette: [(u8, u8, u8); 16],
+    window_title: String,
+    // Slot numbers are appended to it
+    state_path: String,
+    vsync: bool,
+}
+
+impl SdlFrontend {
+    fn new(
+        window_title: String,
+        palette: [(u8, u8, u8); 16],
+        state_path: String,
+        vsync: bool,
+    ) -> Self {
+        let sdl_context = sdl2::init().unwrap();
+
+        let audio_subsystem = sdl_context.audio().unwrap();
+
+        let desired_spec = AudioSpecDesired {
+            freq: Some(44100),
+            channels: Some(1),
+            samples: Some(512),
+        };
+
+        let buzzer = audio_subsystem
+            .open_playback(None, &desired_spec, |spec| {
+                // initialize the audio callback
+                Buzzer {
+                    freq: spec.freq as f32,
+                    phase_inc: 440.0 / spec.freq as f32,
+                    phase: 0.0,
+                    volume: 0.25,
+                    pattern: None,
+                    pattern_rate: 4000.0,
+                    pattern_phase: 0.0,
+                }
+            })
+            .unwrap();
+
+        let video_subsystem = sdl_context.video().unwrap();
+        let window = video_subsystem
+            .window(&window_title, X_SIZE, Y_SIZE)
+            .position_centered()
+            .opengl()
+            .build()
+            .unwrap();
+
+        let mut canvas = if vsync {
+            window.into_canvas().present_vsync().build().unwrap()
+        } else {
+            window.into_canvas().build().unwrap()
+        };
+
+        canvas.set_draw_color(Color::RGB(0, 0, 0));
+        canvas.clear();
+        canvas.present();
 
-    canvas.set_draw_color(Color::RGB(0, 0, 0));
-    canvas.clear();
-    canvas.present();
+        Self {
+            canvas,
+            event_pump: sdl_context.event_pump().unwrap(),
+            buzzer,
+            keyboard: [false; 16],
+            palette,
+            window_title,
+            state_path,
+            vsync,
+        }
+    }
+}
 
-    let mut machine = Chip8::new();
-    machine.init();
+impl Frontend for SdlFrontend {
+    fn present(&mut self, machine: &Chip8) {
+        if machine.video_memory_tainted {
+            draw_canvas(machine, &self.palette, &mut self.canvas);
+        }
 
-    let rom = read_file(&args[1]);
+        self.canvas.present();
+    }
 
-    machine.load_rom(0x200, &rom);
-    machine.set_pc(0x200);
+    fn set_buzzer(&mut self, active: bool, pattern: Option<[u8; 16]>, pattern_rate: f32) {
+        {
+            let mut buzzer = self.buzzer.lock();
+            buzzer.pattern = pattern;
+            buzzer.pattern_rate = pattern_rate;
+        }
 
-    let mut keyboard = [false; 16];
-    let mut event_pump = sdl_context.event_pump().unwrap();
+        if active {
+            self.buzzer.resume();
+        } else {
+            self.buzzer.pause();
+        }
+    }
 
-    //600hz
-    let default_clock_cycle = Duration::new(0, 10_000_000 / 6);
-    let mut current_clock_cycle = default_clock_cycle;
+    fn poll_keypad(&mut self) -> [bool; 16] {
+        self.keyboard
+    }
 
-    'event_loop: loop {
-        let looping_time = Instant::now();
-        for event in event_pump.poll_iter() {
+    fn poll_commands(&mut self) -> Vec<Command> {
+        let mut commands = vec![];
+        for event in self.event_pump.poll_iter() {
             match event {
                 Event::Quit { .. }
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
-                } => break 'event_loop,
+                } => commands.push(Command::Quit),
 
                 Event::KeyDown {
-                    keycode: Some(Keycode::O),
+                    keycode: Some(keycode),
+                    keymod,
                     ..
                 } => {
-                    machine = Chip8::default();
-                    machine.init();
-                    machine.load_rom(0x200, &rom);
-                    machine.set_pc(0x200);
+                    if let Some(command) = handle_key(&mut self.keyboard, keycode, keymod, true) {
+                        commands.push(command);
+                    }
                 }
 
-                Event::KeyDown {
-                    keycode: Some(Keycode::KpPlus),
-                    ..
-                } => current_clock_cycle /= 2,
-
-                Event::KeyDown {
-                    keycode: Some(Keycode::KpMinus),
-                    ..
-                } => current_clock_cycle *= 2,
-
-                Event::KeyDown {
-                    keycode: Some(Keycode::Kp0),
-                    ..
-                } => current_clock_cycle = default_clock_cycle,
-
-                Event::KeyDown {
-                    keycode: Some(keycode),
-                    ..
-                } => handle_key(&mut keyboard, &mut machine, keycode, true),
-
                 Event::KeyUp {
                     keycode: Some(keycode),
+                    keymod,
                     ..
-                } => handle_key(&mut keyboard, &mut machine, keycode, false),
+                } => {
+                    if let Some(command) = handle_key(&mut self.keyboard, keycode, keymod, false) {
+                        commands.push(command);
+                    }
+                }
 
                 _ => {}
             }
         }
+        commands
+    }
 
-        process_sound(&machine, &buzzer);
-        machine.tick_clock(&keyboard);
+    fn report_fault(&mut self, exception: &Chip8Exception) {
+        error!("CPU halted: {}", exception);
+        self.canvas
+            .window_mut()
+            .set_title(&format!("{} [HALTED: {}]", self.window_title, exception))
+            .unwrap();
+    }
 
-        if machine.video_memory_tainted {
-            draw_canvas(&machine, &mut canvas);
+    fn clear_fault(&mut self) {
+        self.canvas
+            .window_mut()
+            .set_title(&self.window_title)
+            .unwrap();
+    }
+
+    fn vsync(&self) -> bool {
+        self.vsync
+    }
+
+    fn write_state(&mut self, slot: u8, state: &[u8]) {
+        let path = format!("{}{}", self.state_path, slot);
+        match fs::write(&path, state) {
+            Ok(()) => info!("Saved state to {}", path),
+            Err(e) => error!("Unable to save state to {}: {}", path, e),
         }
+    }
 
-        canvas.present();
-        let elapsed_time = looping_time.elapsed();
-
-        //debug!("Cycle: {}us, ", elapsed_time.as_micros());
-
-        if let Some(sleep_required) = current_clock_cycle.checked_sub(elapsed_time) {
-            let slept_time = Instant::now();
-            spin_sleep::sleep(sleep_required);
-            debug!(
-                "Loop time: {}us, Sleep Required: {}us, Slept for: {}us, Error: {}us",
-                elapsed_time.as_micros(),
-                sleep_required.as_micros(),
-                slept_time.elapsed().as_micros(),
-                sleep_required.as_micros() as i64 - slept_time.elapsed().as_micros() as i64,
-            );
+    fn read_state(&mut self, slot: u8) -> Option<Vec<u8>> {
+        let path = format!("{}{}", self.state_path, slot);
+        match fs::read(&path) {
+            Ok(state) => {
+                info!("Loaded state from {}", path);
+                Some(state)
+            }
+            Err(e) => {
+                error!("Unable to load state from {}: {}", path, e);
+                None
+            }
         }
     }
 }
 
-pub fn process_sound<T: sdl2::audio::AudioCallback>(
+fn draw_canvas<T: RenderTarget>(
     machine: &Chip8,
-    device: &sdl2::audio::AudioDevice<T>,
+    palette: &[(u8, u8, u8); 16],
+    canvas: &mut Canvas<T>,
 ) {
-    if machine.st == 0 {
-        device.pause();
-    } else {
-        device.resume();
-    }
-}
-
-fn draw_canvas<T: RenderTarget>(machine: &Chip8, canvas: &mut Canvas<T>) {
-    let color_on: Color = Color::RGB(255, 255, 255);
-    let color_off: Color = Color::RGB(0, 0, 0);
+    let cell_pixel_side = X_SIZE / machine.width() as u32;
 
-    for y in 0..32 {
-        for x in 0..64 {
-            let pixel_value = machine.get_pixel(x, y);
-            let color = if pixel_value { color_on } else { color_off };
-            canvas.set_draw_color(color);
-            let (px, py) = (x as u32 * CELL_PIXEL_SIDE, y as u32 * CELL_PIXEL_SIDE);
+    for y in 0..machine.height() {
+        for x in 0..machine.width() {
+            let (r, g, b) = palette[machine.get_pixel(x, y) as usize];
+            canvas.set_draw_color(Color::RGB(r, g, b));
+            let (px, py) = (x as u32 * cell_pixel_side, y as u32 * cell_pixel_side);
             canvas
                 .fill_rect(Rect::new(
                     px as i32,
                     py as i32,
-                    CELL_PIXEL_SIDE,
-                    CELL_PIXEL_SIDE,
+                    cell_pixel_side,
+                    cell_pixel_side,
                 ))
                 .unwrap();
         }
     }
 }
 
-fn read_file(path: &str) -> [u8; 3584] {
-    let mut f = File::open(path).unwrap();
-    let mut buffer = [0u8; 3584];
-    let _len = f.read(&mut buffer).unwrap();
-    buffer
-}
-
 pub fn handle_key(
     pad_state: &mut [bool; 16],
-    machine: &mut Chip8,
     keycode: Keycode,
+    keymod: Mod,
     pressed: bool,
-) {
+) -> Option<Command> {
     /*
         HEX PAD | QWERTY
         1 2 3 C | 1 2 3 4
@@ -234,40 +540,72 @@ pub fn handle_key(
         Keycode::V => pad_state[0xF] = pressed,
 
         // other keys
-        Keycode::P => {
-            if pressed {
-                machine.int()
-            }
-        }
-        Keycode::N => {
-            if pressed {
-                machine.next(pad_state)
-            }
-        }
+        Keycode::P if pressed => return Some(Command::TogglePause),
+        Keycode::N if pressed => return Some(Command::Step),
+        Keycode::O if pressed => return Some(Command::Reset),
+        Keycode::KpPlus if pressed => return Some(Command::SpeedUp),
+        Keycode::KpMinus if pressed => return Some(Command::SpeedDown),
+        Keycode::Kp0 if pressed => return Some(Command::ResetSpeed),
+        Keycode::Backspace if pressed => return Some(Command::Rewind),
+        Keycode::Backspace => return Some(Command::StopRewind),
         _ => {}
     }
+
+    // F1-F9 load the numbered save state slot, with shift they save it
+    let slot = match keycode {
+        Keycode::F1 => 1,
+        Keycode::F2 => 2,
+        Keycode::F3 => 3,
+        Keycode::F4 => 4,
+        Keycode::F5 => 5,
+        Keycode::F6 => 6,
+        Keycode::F7 => 7,
+        Keycode::F8 => 8,
+        Keycode::F9 => 9,
+        _ => return None,
+    };
+    if !pressed {
+        None
+    } else if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+        Some(Command::SaveState(slot))
+    } else {
+        Some(Command::LoadState(slot))
+    }
 }
 
 use sdl2::audio::{AudioCallback, AudioSpecDesired};
 // https://docs.rs/sdl2/0.32.2/sdl2/audio/index.html#example
-struct SquareWave {
+struct Buzzer {
+    freq: f32,
     phase_inc: f32,
     phase: f32,
     volume: f32,
+    // XO-CHIP 1-bit audio pattern, played at pattern_rate bits per second
+    pattern: Option<[u8; 16]>,
+    pattern_rate: f32,
+    pattern_phase: f32,
 }
 
-impl AudioCallback for SquareWave {
+impl AudioCallback for Buzzer {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
         for x in out.iter_mut() {
-            *x = if self.phase <= 0.5 {
-                self.volume
-            } else {
-                -self.volume
+            let high = match self.pattern {
+                Some(pattern) => {
+                    let bit = self.pattern_phase as usize;
+                    self.pattern_phase =
+                        (self.pattern_phase + self.pattern_rate / self.freq) % 128.0;
+                    pattern[bit / 8] & (0x80 >> (bit % 8)) != 0
+                }
+                // Generate a square wave
+                None => {
+                    let high = self.phase <= 0.5;
+                    self.phase = (self.phase + self.phase_inc) % 1.0;
+                    high
+                }
             };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+            *x = if high { self.volume } else { -self.volume };
         }
     }
 }
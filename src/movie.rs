@@ -0,0 +1,241 @@
+use std::fmt;
+use std::fs;
+
+use log::info;
+
+use crate::chip8::{Chip8, MemoryPolicy};
+use crate::database;
+use crate::quirks::Quirks;
+use crate::random::{Rng, RngAlgorithm};
+use crate::savestate::{Reader, SaveStateError, Writer};
+use crate::timing::Timing;
+
+// Movies start with the magic and the format version, then the settings the machine was booted
+// with in the order of `Movie::to_bytes` and one u16 keypad per frame, all little endian
+const MAGIC: &[u8; 4] = b"C8MV";
+pub const VERSION: u8 = 1;
+
+#[derive(Debug)]
+pub enum MovieError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    Truncated,
+    InvalidValue(&'static str),
+}
+
+impl From<SaveStateError> for MovieError {
+    fn from(error: SaveStateError) -> Self {
+        match error {
+            SaveStateError::BadMagic => MovieError::BadMagic,
+            SaveStateError::UnsupportedVersion(version) => MovieError::UnsupportedVersion(version),
+            SaveStateError::Truncated => MovieError::Truncated,
+            SaveStateError::InvalidValue(field) => MovieError::InvalidValue(field),
+        }
+    }
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::BadMagic => write!(f, "Not a movie"),
+            MovieError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported movie version {}", version)
+            }
+            MovieError::Truncated => write!(f, "Movie is truncated"),
+            MovieError::InvalidValue(field) => write!(f, "Invalid {} in movie", field),
+        }
+    }
+}
+
+/// Keypad input of a run, frame by frame, along with everything needed to boot the same machine
+/// again and replay it exactly
+#[derive(Clone)]
+pub struct Movie {
+    /// SHA-1 of the ROM, as given by `database::hash`
+    pub rom_hash: String,
+    pub seed: u32,
+    pub rng_algorithm: RngAlgorithm,
+    pub quirks: Quirks,
+    pub xo_chip: bool,
+    pub memory_policy: MemoryPolicy,
+    pub timing: Timing,
+    pub instructions_per_frame: usize,
+    /// Keypad of every frame the machine ran, bit N is key N
+    pub frames: Vec<u16>,
+}
+
+impl Movie {
+    /// Empty movie for `machine`, just booted from `rom` by `Chip8::with_seed(seed)`
+    pub fn new(machine: &Chip8, rom: &[u8], seed: u32) -> Self {
+        Self {
+            rom_hash: database::hash(rom),
+            seed,
+            rng_algorithm: machine.rng.algorithm,
+            quirks: machine.quirks,
+            xo_chip: machine.xo_chip,
+            memory_policy: machine.memory_policy,
+            timing: machine.timing,
+            instructions_per_frame: machine.instructions_per_frame,
+            frames: vec![],
+        }
+    }
+
+    /// Set up a new machine like the recorded one, before its ROM is loaded
+    pub fn configure(&self, machine: &mut Chip8) {
+        machine.rng = Rng::new(self.seed, self.rng_algorithm);
+        machine.quirks = self.quirks;
+        machine.xo_chip = self.xo_chip;
+        machine.memory_policy = self.memory_policy;
+        machine.timing = self.timing;
+        machine.instructions_per_frame = self.instructions_per_frame;
+    }
+
+    /// Read the movie at `path`, which must have been recorded with `rom`
+    pub fn load(path: &str, rom: &[u8]) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+        let movie =
+            Self::from_bytes(&data).map_err(|e| format!("Unable to load {}: {}", path, e))?;
+        if movie.rom_hash != database::hash(rom) {
+            return Err(format!(
+                "{} was recorded with another ROM ({})",
+                path, movie.rom_hash
+            ));
+        }
+        Ok(movie)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Writer(MAGIC.to_vec());
+        out.u8(VERSION);
+
+        out.bytes(self.rom_hash.as_bytes());
+        out.u32(self.seed);
+        out.rng_algorithm(self.rng_algorithm);
+        out.quirks(self.quirks);
+        out.bool(self.xo_chip);
+        out.u8(match self.memory_policy {
+            MemoryPolicy::Strict => 0,
+            MemoryPolicy::Wrap => 1,
+        });
+        out.u8(match self.timing {
+            Timing::InstructionsPerFrame => 0,
+            Timing::CosmacVip => 1,
+        });
+        out.u32(self.instructions_per_frame as u32);
+
+        out.u32(self.frames.len() as u32);
+        for keypad in &self.frames {
+            out.bytes(&keypad.to_le_bytes());
+        }
+        out.0
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, MovieError> {
+        let mut input = Reader::new(data);
+        if input.take(MAGIC.len())? != MAGIC {
+            return Err(MovieError::BadMagic);
+        }
+        match input.u8()? {
+            VERSION => {}
+            version => return Err(MovieError::UnsupportedVersion(version)),
+        }
+
+        let rom_hash = String::from_utf8(input.take(40)?.to_vec())
+            .map_err(|_| MovieError::InvalidValue("ROM hash"))?;
+        let seed = input.u32()?;
+        let rng_algorithm = input.rng_algorithm()?;
+        let quirks = input.quirks()?;
+        let xo_chip = input.bool()?;
+        let memory_policy = match input.u8()? {
+            0 => MemoryPolicy::Strict,
+            1 => MemoryPolicy::Wrap,
+            _ => return Err(MovieError::InvalidValue("memory policy")),
+        };
+        let timing = match input.u8()? {
+            0 => Timing::InstructionsPerFrame,
+            1 => Timing::CosmacVip,
+            _ => return Err(MovieError::InvalidValue("timing")),
+        };
+        let instructions_per_frame = input.u32()? as usize;
+        if instructions_per_frame == 0 {
+            return Err(MovieError::InvalidValue("instructions per frame"));
+        }
+
+        let frames = (0..input.u32()?)
+            .map(|_| Ok(u16::from_le_bytes(input.array()?)))
+            .collect::<Result<_, MovieError>>()?;
+
+        Ok(Self {
+            rom_hash,
+            seed,
+            rng_algorithm,
+            quirks,
+            xo_chip,
+            memory_policy,
+            timing,
+            instructions_per_frame,
+            frames,
+        })
+    }
+}
+
+pub fn keypad_to_bits(keypad: &[bool; 16]) -> u16 {
+    keypad
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (key, held)| bits | (*held as u16) << key)
+}
+
+pub fn keypad_from_bits(bits: u16) -> [bool; 16] {
+    let mut keypad = [false; 16];
+    for (key, held) in keypad.iter_mut().enumerate() {
+        *held = bits & 1 << key != 0;
+    }
+    keypad
+}
+
+/// Whether the keypad of every frame the machine runs is recorded, replaced by a movie's, or
+/// left alone
+pub enum MovieMode {
+    Off,
+    Recording(Movie),
+    Playing { movie: Movie, frame: usize },
+}
+
+impl MovieMode {
+    pub fn is_active(&self) -> bool {
+        !matches!(self, MovieMode::Off)
+    }
+
+    /// Keypad to run the next frame with, playback goes back to the live keypad once the
+    /// movie is over
+    pub fn next_frame(&mut self, keypad: [bool; 16]) -> [bool; 16] {
+        match self {
+            MovieMode::Off => keypad,
+            MovieMode::Recording(movie) => {
+                movie.frames.push(keypad_to_bits(&keypad));
+                keypad
+            }
+            MovieMode::Playing { movie, frame } => match movie.frames.get(*frame) {
+                Some(bits) => {
+                    *frame += 1;
+                    keypad_from_bits(*bits)
+                }
+                None => {
+                    info!("Movie finished after {} frames", frame);
+                    *self = MovieMode::Off;
+                    keypad
+                }
+            },
+        }
+    }
+
+    /// Start over along with the machine
+    pub fn restart(&mut self) {
+        match self {
+            MovieMode::Off => {}
+            MovieMode::Recording(movie) => movie.frames.clear(),
+            MovieMode::Playing { frame, .. } => *frame = 0,
+        }
+    }
+}
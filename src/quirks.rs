@@ -0,0 +1,135 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// How much FX55/FX65 advance I after storing/loading registers
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum MemoryIncrement {
+    /// I += X + 1, like the COSMAC VIP interpreter
+    XPlusOne,
+    /// I += X, like CHIP-48
+    X,
+    /// I is left untouched
+    None,
+}
+
+/// Behaviours that differ between the platforms CHIP-8 programs were written for.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Quirks {
+    /// 8XY6/8XYE shift VX in place instead of shifting VY into VX
+    pub shift_vx: bool,
+    pub memory_increment: MemoryIncrement,
+    /// BNNN is read as BXNN and jumps to XNN + VX instead of NNN + V0
+    pub jump_vx: bool,
+    /// 8XY1/8XY2/8XY3 reset VF to 0
+    pub vf_reset: bool,
+    /// DXYN clips sprites at the screen edges instead of wrapping them around
+    pub clip_sprites: bool,
+    /// DXYN waits for the next vertical blank before drawing
+    pub display_wait: bool,
+    /// FX0A completes as soon as any key is held instead of waiting for a key to be pressed and
+    /// released
+    pub key_press_only: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self {
+            shift_vx: true,
+            memory_increment: MemoryIncrement::None,
+            jump_vx: false,
+            vf_reset: false,
+            clip_sprites: false,
+            display_wait: false,
+            key_press_only: false,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Platform {
+    CosmacVip,
+    Chip48,
+    SuperChipLegacy,
+    SuperChipModern,
+    XoChip,
+}
+
+impl Platform {
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Platform::CosmacVip => Quirks {
+                shift_vx: false,
+                memory_increment: MemoryIncrement::XPlusOne,
+                jump_vx: false,
+                vf_reset: true,
+                clip_sprites: true,
+                display_wait: true,
+                key_press_only: false,
+            },
+            Platform::Chip48 => Quirks {
+                shift_vx: true,
+                memory_increment: MemoryIncrement::X,
+                jump_vx: true,
+                vf_reset: false,
+                clip_sprites: true,
+                display_wait: false,
+                key_press_only: false,
+            },
+            Platform::SuperChipLegacy => Quirks {
+                shift_vx: true,
+                memory_increment: MemoryIncrement::None,
+                jump_vx: true,
+                vf_reset: false,
+                clip_sprites: true,
+                display_wait: true,
+                key_press_only: false,
+            },
+            Platform::SuperChipModern => Quirks {
+                shift_vx: true,
+                memory_increment: MemoryIncrement::None,
+                jump_vx: true,
+                vf_reset: false,
+                clip_sprites: true,
+                display_wait: false,
+                key_press_only: false,
+            },
+            Platform::XoChip => Quirks {
+                shift_vx: false,
+                memory_increment: MemoryIncrement::XPlusOne,
+                jump_vx: false,
+                vf_reset: false,
+                clip_sprites: false,
+                display_wait: false,
+                key_press_only: false,
+            },
+        }
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_lowercase().as_str() {
+            "vip" | "cosmac-vip" | "chip8" | "chip-8" => Ok(Platform::CosmacVip),
+            "chip48" | "chip-48" => Ok(Platform::Chip48),
+            "schip-legacy" | "schip1.1" | "superchip-legacy" => Ok(Platform::SuperChipLegacy),
+            "schip" | "schip-modern" | "superchip" => Ok(Platform::SuperChipModern),
+            "xo-chip" | "xochip" => Ok(Platform::XoChip),
+            _ => Err(format!("Unknown platform {}", name)),
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Platform::CosmacVip => "COSMAC VIP",
+            Platform::Chip48 => "CHIP-48",
+            Platform::SuperChipLegacy => "SUPER-CHIP 1.1 (legacy)",
+            Platform::SuperChipModern => "SUPER-CHIP (modern)",
+            Platform::XoChip => "XO-CHIP",
+        };
+        write!(f, "{}", name)
+    }
+}
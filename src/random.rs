@@ -0,0 +1,71 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum RngAlgorithm {
+    /// 32 bit xorshift, only advanced by CXNN
+    Xorshift,
+    /// The COSMAC VIP interpreter's generator: the interrupt counts the low seed byte up every
+    /// frame and CXNN adds the memory byte it points to in page 0 to the high seed byte, so the
+    /// numbers depend on timing. The VIP read its own interpreter there, here it is the font.
+    CosmacVip,
+}
+
+/// Random number generator behind CXNN. It is part of the machine state so that a run can be
+/// reproduced from its seed.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Rng {
+    pub algorithm: RngAlgorithm,
+    pub state: u32,
+}
+
+impl Rng {
+    pub fn new(seed: u32, algorithm: RngAlgorithm) -> Self {
+        Self {
+            algorithm,
+            // Xorshift never leaves 0
+            state: if seed == 0 { 0x2545_f491 } else { seed },
+        }
+    }
+
+    /// Seed from the clock, for runs that don't need to be reproduced
+    pub fn from_time(algorithm: RngAlgorithm) -> Self {
+        Self::new(time_seed(), algorithm)
+    }
+
+    /// Called by the 60 Hz timer tick
+    pub fn tick(&mut self) {
+        if self.algorithm == RngAlgorithm::CosmacVip {
+            let [low, high, ..] = self.state.to_le_bytes();
+            self.state = u32::from_le_bytes([low.wrapping_add(1), high, 0, 0]);
+        }
+    }
+
+    /// Next random byte, `page` is the first 256 bytes of memory
+    pub fn next_byte(&mut self, page: &[u8]) -> u8 {
+        match self.algorithm {
+            RngAlgorithm::Xorshift => {
+                let mut x = self.state;
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                self.state = x;
+                (x >> 24) as u8
+            }
+            RngAlgorithm::CosmacVip => {
+                let [low, high, ..] = self.state.to_le_bytes();
+                let low = low.wrapping_add(1);
+                let high = high.wrapping_add(page[low as usize]);
+                self.state = u32::from_le_bytes([low, high, 0, 0]);
+                high
+            }
+        }
+    }
+}
+
+/// A seed that differs from run to run
+pub fn time_seed() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.subsec_nanos() ^ time.as_secs() as u32)
+        .unwrap_or_default()
+}
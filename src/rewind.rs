@@ -0,0 +1,91 @@
+use std::collections::VecDeque;
+
+/// History of save states, one per frame. Only the newest is kept whole, every older one is
+/// stored as the RLE compressed XOR against its successor: consecutive frames barely differ, so
+/// each of those is a few dozen bytes.
+pub struct Rewind {
+    newest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+    capacity: usize,
+}
+
+impl Rewind {
+    /// Keep up to `capacity` snapshots, 0 disables rewinding
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            newest: None,
+            deltas: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.newest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if let Some(older) = self.newest.replace(state) {
+            let delta = diff(&older, self.newest.as_ref().unwrap());
+            self.deltas.push_back(rle_encode(&delta));
+        }
+        // The oldest snapshot only depends on its successors, it can be dropped on its own
+        while self.len() > self.capacity {
+            self.deltas.pop_front();
+        }
+    }
+
+    /// Take the newest snapshot out of the history
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let newest = self.newest.take()?;
+        self.newest = self
+            .deltas
+            .pop_back()
+            .map(|delta| undo(&newest, &rle_decode(&delta)));
+        Some(newest)
+    }
+}
+
+// Length of `older` followed by the XOR of both states, the shorter one padded with zeros
+fn diff(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let len = older.len().max(newer.len());
+    let mut delta = (older.len() as u32).to_le_bytes().to_vec();
+    delta.extend((0..len).map(|i| older.get(i).unwrap_or(&0) ^ newer.get(i).unwrap_or(&0)));
+    delta
+}
+
+fn undo(newer: &[u8], delta: &[u8]) -> Vec<u8> {
+    let (len, xor) = delta.split_at(4);
+    let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    (0..len)
+        .map(|i| xor[i] ^ newer.get(i).unwrap_or(&0))
+        .collect()
+}
+
+// (count, byte) pairs
+fn rle_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut bytes = data.iter().peekable();
+    while let Some(&byte) = bytes.next() {
+        let mut count = 1u8;
+        while count < u8::MAX && bytes.next_if_eq(&&byte).is_some() {
+            count += 1;
+        }
+        out.push(count);
+        out.push(byte);
+    }
+    out
+}
+
+fn rle_decode(data: &[u8]) -> Vec<u8> {
+    data.chunks_exact(2)
+        .flat_map(|pair| std::iter::repeat_n(pair[1], pair[0] as usize))
+        .collect()
+}
@@ -0,0 +1,293 @@
+use std::convert::TryInto;
+use std::fmt;
+
+use crate::chip8::{Chip8, KeyWait, State, HIRES_HEIGHT, HIRES_WIDTH, XO_CHIP_MEMORY_SIZE};
+use crate::quirks::{MemoryIncrement, Quirks};
+use crate::random::{Rng, RngAlgorithm};
+use crate::timing;
+
+// Save states start with the magic and the format version, followed by the machine fields in
+// the order of `Chip8::save_state`, all integers little endian. Bump the version whenever the
+// layout changes.
+const MAGIC: &[u8; 4] = b"C8ST";
+pub const VERSION: u8 = 2;
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    Truncated,
+    InvalidValue(&'static str),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveStateError::BadMagic => write!(f, "Not a save state"),
+            SaveStateError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported save state version {}", version)
+            }
+            SaveStateError::Truncated => write!(f, "Save state is truncated"),
+            SaveStateError::InvalidValue(field) => write!(f, "Invalid {} in save state", field),
+        }
+    }
+}
+
+impl Chip8 {
+    /// Snapshot of everything a running program can observe or change, RNG included. Configuration
+    /// the host picks (speed, timing, memory policy) and the persistent RPL flags are left out.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = Writer(MAGIC.to_vec());
+        out.u8(VERSION);
+
+        let memory = &self.memory[..self.address_space()];
+        out.u32(memory.len() as u32);
+        out.bytes(memory);
+        out.bytes(&self.video_memory);
+        out.bool(self.hires);
+        out.bool(self.xo_chip);
+        out.u8(self.planes);
+        out.bool(self.audio_pattern.is_some());
+        out.bytes(&self.audio_pattern.unwrap_or_default());
+        out.u8(self.pitch);
+
+        for addr in self.stack {
+            out.u32(addr as u32);
+        }
+        out.bytes(&self.v);
+        for key in self.keys {
+            out.bool(key);
+        }
+        out.u32(self.i as u32);
+        out.u8(self.dt);
+        out.u8(self.st);
+        out.u32(self.pc as u32);
+        out.u8(self.sp as u8);
+
+        match self.key_wait {
+            KeyWait::None => out.bytes(&[0, 0, 0]),
+            KeyWait::Press(vx) => out.bytes(&[1, vx as u8, 0]),
+            KeyWait::Release(vx, key) => out.bytes(&[2, vx as u8, key]),
+        }
+        out.bool(self.waiting_for_vblank);
+        out.bool(self.vblank);
+        out.u8(match self.state {
+            State::Paused => 0,
+            State::Running => 1,
+            State::Halted => 2,
+        });
+        out.u32(self.cycle_budget as u32);
+
+        out.quirks(self.quirks);
+        out.rng_algorithm(self.rng.algorithm);
+        out.u32(self.rng.state);
+
+        out.0
+    }
+
+    /// Restore a snapshot taken by `save_state`, the machine is left untouched on error
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut input = Reader::new(state);
+        if input.take(MAGIC.len())? != MAGIC {
+            return Err(SaveStateError::BadMagic);
+        }
+        match input.u8()? {
+            VERSION => {}
+            version => return Err(SaveStateError::UnsupportedVersion(version)),
+        }
+
+        // Parse into a copy so a bad state can't leave the machine half loaded
+        let mut machine = Chip8 {
+            memory: [0; XO_CHIP_MEMORY_SIZE],
+            ..*self
+        };
+
+        let memory_size = input.u32()? as usize;
+        if memory_size > XO_CHIP_MEMORY_SIZE {
+            return Err(SaveStateError::InvalidValue("memory size"));
+        }
+        machine.memory[..memory_size].copy_from_slice(input.take(memory_size)?);
+        machine
+            .video_memory
+            .copy_from_slice(input.take(HIRES_WIDTH * HIRES_HEIGHT)?);
+        // Pixels hold one bit per plane and index the 16 color palette
+        if machine.video_memory.iter().any(|&pixel| pixel > 0xF) {
+            return Err(SaveStateError::InvalidValue("pixel"));
+        }
+        machine.hires = input.bool()?;
+        machine.xo_chip = input.bool()?;
+        machine.planes = input.u8()?;
+        if machine.planes > 0xF {
+            return Err(SaveStateError::InvalidValue("planes"));
+        }
+        let has_audio_pattern = input.bool()?;
+        let audio_pattern = input.array()?;
+        machine.audio_pattern = Some(audio_pattern).filter(|_| has_audio_pattern);
+        machine.pitch = input.u8()?;
+
+        // Return addresses follow a 2 byte CALL anywhere in memory, with wrapping memory one
+        // at the very last byte returns past the end
+        let address_space = machine.address_space();
+        for addr in machine.stack.iter_mut() {
+            *addr = input.u32()? as usize;
+            if *addr > address_space + 1 {
+                return Err(SaveStateError::InvalidValue("stack"));
+            }
+        }
+        machine.v = input.array()?;
+        for key in machine.keys.iter_mut() {
+            *key = input.bool()?;
+        }
+        machine.i = input.u32()? as usize;
+        machine.dt = input.u8()?;
+        machine.st = input.u8()?;
+        machine.pc = input.u32()? as usize;
+        machine.sp = input.u8()? as usize;
+        if machine.sp > machine.stack.len() {
+            return Err(SaveStateError::InvalidValue("stack pointer"));
+        }
+
+        let [kind, vx, key] = input.array()?;
+        if vx > 0xF || key > 0xF {
+            return Err(SaveStateError::InvalidValue("key wait"));
+        }
+        machine.key_wait = match kind {
+            0 => KeyWait::None,
+            1 => KeyWait::Press(vx as usize),
+            2 => KeyWait::Release(vx as usize, key),
+            _ => return Err(SaveStateError::InvalidValue("key wait")),
+        };
+        machine.waiting_for_vblank = input.bool()?;
+        machine.vblank = input.bool()?;
+        machine.state = match input.u8()? {
+            0 => State::Paused,
+            1 => State::Running,
+            2 => State::Halted,
+            _ => return Err(SaveStateError::InvalidValue("state")),
+        };
+        machine.cycle_budget = input.u32()? as i32;
+        // A frame stopped early keeps up to a whole budget, one that ran over owes its last
+        // instruction's cycles
+        if !(-timing::VIP_MAX_INSTRUCTION_CYCLES..=timing::VIP_CYCLES_PER_FRAME)
+            .contains(&machine.cycle_budget)
+        {
+            return Err(SaveStateError::InvalidValue("cycle budget"));
+        }
+
+        machine.quirks = input.quirks()?;
+        machine.rng = Rng {
+            algorithm: input.rng_algorithm()?,
+            state: input.u32()?,
+        };
+
+        machine.video_memory_tainted = true;
+        *self = machine;
+        Ok(())
+    }
+}
+
+// Little endian encoding shared with movie files
+pub(crate) struct Writer(pub Vec<u8>);
+
+impl Writer {
+    pub fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.0.push(value as u8);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    pub fn quirks(&mut self, quirks: Quirks) {
+        self.bool(quirks.shift_vx);
+        self.u8(match quirks.memory_increment {
+            MemoryIncrement::XPlusOne => 0,
+            MemoryIncrement::X => 1,
+            MemoryIncrement::None => 2,
+        });
+        self.bool(quirks.jump_vx);
+        self.bool(quirks.vf_reset);
+        self.bool(quirks.clip_sprites);
+        self.bool(quirks.display_wait);
+        self.bool(quirks.key_press_only);
+    }
+
+    pub fn rng_algorithm(&mut self, algorithm: RngAlgorithm) {
+        self.u8(match algorithm {
+            RngAlgorithm::Xorshift => 0,
+            RngAlgorithm::CosmacVip => 1,
+        });
+    }
+}
+
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], SaveStateError> {
+        if self.data.len() < len {
+            return Err(SaveStateError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], SaveStateError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, SaveStateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SaveStateError::InvalidValue("flag")),
+        }
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SaveStateError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn quirks(&mut self) -> Result<Quirks, SaveStateError> {
+        Ok(Quirks {
+            shift_vx: self.bool()?,
+            memory_increment: match self.u8()? {
+                0 => MemoryIncrement::XPlusOne,
+                1 => MemoryIncrement::X,
+                2 => MemoryIncrement::None,
+                _ => return Err(SaveStateError::InvalidValue("memory increment quirk")),
+            },
+            jump_vx: self.bool()?,
+            vf_reset: self.bool()?,
+            clip_sprites: self.bool()?,
+            display_wait: self.bool()?,
+            key_press_only: self.bool()?,
+        })
+    }
+
+    pub fn rng_algorithm(&mut self) -> Result<RngAlgorithm, SaveStateError> {
+        match self.u8()? {
+            0 => Ok(RngAlgorithm::Xorshift),
+            1 => Ok(RngAlgorithm::CosmacVip),
+            _ => Err(SaveStateError::InvalidValue("RNG algorithm")),
+        }
+    }
+}
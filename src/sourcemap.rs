@@ -0,0 +1,84 @@
+use std::fs;
+use std::path::Path;
+
+/// Where each instruction of a ROM came from, as written by an assembler. The text format has
+/// one `ADDR FILE:LINE` line per instruction, `ADDR` in hex, e.g. `0x0200 game.asm:12`.
+pub struct SourceMap {
+    entries: Vec<SourceLocation>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct SourceLocation {
+    pub addr: usize,
+    pub file: String,
+    pub line: usize,
+}
+
+impl SourceMap {
+    pub fn new(entries: Vec<SourceLocation>) -> Self {
+        Self { entries }
+    }
+
+    /// Read the map at `path`, relative source paths in it being relative to its directory
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text =
+            fs::read_to_string(path).map_err(|e| format!("Unable to read {}: {}", path, e))?;
+        let mut source_map = Self::parse(&text).map_err(|e| format!("{}: {}", path, e))?;
+        let dir = match Path::new(path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+        for entry in &mut source_map.entries {
+            entry.file = dir.join(&entry.file).to_string_lossy().into_owned();
+        }
+        Ok(source_map)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut entries = vec![];
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || format!("Invalid source map entry on line {}", number + 1);
+            let (addr, location) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let (file, source_line) = location.trim().rsplit_once(':').ok_or_else(invalid)?;
+            entries.push(SourceLocation {
+                addr: usize::from_str_radix(addr.trim_start_matches("0x"), 16)
+                    .map_err(|_| invalid())?,
+                file: file.to_string(),
+                line: source_line.parse().map_err(|_| invalid())?,
+            });
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn to_text(&self) -> String {
+        self.entries
+            .iter()
+            .map(|entry| format!("0x{:04x} {}:{}\n", entry.addr, entry.file, entry.line))
+            .collect()
+    }
+
+    pub fn entries(&self) -> &[SourceLocation] {
+        &self.entries
+    }
+
+    /// Source line of the instruction at `addr`
+    pub fn location(&self, addr: usize) -> Option<&SourceLocation> {
+        self.entries.iter().find(|entry| entry.addr == addr)
+    }
+
+    /// First instruction on `line` of `file`, or on the closest line after it with code.
+    /// Files match by name when the paths differ, as editors use absolute paths.
+    pub fn address(&self, file: &str, line: usize) -> Option<&SourceLocation> {
+        let name = Path::new(file).file_name();
+        self.entries
+            .iter()
+            .filter(|entry| entry.file == file || Path::new(&entry.file).file_name() == name)
+            .filter(|entry| entry.line >= line)
+            .min_by_key(|entry| (entry.line, entry.addr))
+    }
+}
@@ -0,0 +1,85 @@
+use crate::chip8::Chip8;
+
+/// How many instructions `Chip8::run_frame` executes per 60 Hz frame
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Timing {
+    /// A fixed `Chip8::instructions_per_frame`, every instruction costs the same
+    InstructionsPerFrame,
+    /// Charge every instruction its machine cycles on the COSMAC VIP interpreter and run as many
+    /// as fit in the frame
+    CosmacVip,
+}
+
+// The VIP's 1802 runs at 1.7609 MHz, 8 clocks per machine cycle
+pub const VIP_FRAME_CYCLES: i32 = 3668;
+// The 1861 display steals 8 bytes of DMA per scanline, for 128 scanlines
+pub const VIP_DMA_CYCLES: i32 = 1024;
+// Interrupt routine: DMA setup and timer countdown
+pub const VIP_INTERRUPT_CYCLES: i32 = 46;
+/// Machine cycles left for the interpreter in every frame
+pub const VIP_CYCLES_PER_FRAME: i32 = VIP_FRAME_CYCLES - VIP_DMA_CYCLES - VIP_INTERRUPT_CYCLES;
+
+// Fetch and dispatch in the interpreter's main loop
+const FETCH_CYCLES: i32 = 40;
+/// Most machine cycles one instruction can take, a 15 row DXYN 7 bits off byte alignment. A frame
+/// can overrun its budget by up to this much.
+pub const VIP_MAX_INSTRUCTION_CYCLES: i32 = FETCH_CYCLES + 26 + 15 * (8 + 4 * 7) + 15 * 12 * 2;
+
+/// Machine cycles the VIP interpreter spends on `op_code` with the machine in its current state.
+/// Based on Laurence Scotford's disassembly of the interpreter, instructions the VIP doesn't
+/// have only pay for the fetch.
+pub fn vip_machine_cycles(machine: &Chip8, op_code: u16) -> i32 {
+    let x = ((op_code & 0x0f00) >> 8) as usize;
+    let y = ((op_code & 0x00f0) >> 4) as usize;
+    let n = (op_code & 0x000f) as usize;
+    let kk = (op_code & 0x00ff) as u8;
+    let (vx, vy) = (machine.v[x], machine.v[y]);
+    let skip = |skipped: bool| if skipped { 4 } else { 0 };
+
+    let cycles = match op_code & 0xf000 {
+        0x0000 => match op_code {
+            0x00E0 => 636,
+            0x00EE => 10,
+            _ => 0,
+        },
+        0x1000 => 12,
+        0x2000 => 26,
+        0x3000 => 10 + skip(vx == kk),
+        0x4000 => 10 + skip(vx != kk),
+        0x5000 if n == 0 => 14 + skip(vx == vy),
+        0x6000 => 6,
+        0x7000 => 10,
+        0x8000 => 44,
+        0x9000 if n == 0 => 14 + skip(vx != vy),
+        0xA000 => 12,
+        0xB000 => 22,
+        0xC000 => 36,
+        0xD000 => sprite_cycles(vx as usize, vy as usize, n),
+        0xE000 => match kk {
+            0x9E => 14 + skip(machine.keys[vx as usize & 0xf]),
+            0xA1 => 14 + skip(!machine.keys[vx as usize & 0xf]),
+            _ => 0,
+        },
+        0xF000 => match kk {
+            0x07 | 0x15 | 0x18 => 10,
+            0x0A => 16,
+            0x1E => 12,
+            0x29 => 16,
+            // Each digit is found by repeated subtraction
+            0x33 => 80 + 16 * (vx / 100 + vx / 10 % 10 + vx % 10) as i32,
+            0x55 | 0x65 => 14 + 14 * (x as i32 + 1),
+            _ => 0,
+        },
+        _ => 0,
+    };
+    FETCH_CYCLES + cycles
+}
+
+// Every sprite row is shifted into place one bit at a time, then XORed into one display byte
+// when byte aligned or two otherwise. Rows past the bottom of the screen are shifted but not drawn.
+fn sprite_cycles(x: usize, y: usize, rows: usize) -> i32 {
+    let shift = (x % 8) as i32;
+    let drawn_rows = rows.min(32 - y % 32) as i32;
+    let bytes_per_row = if shift == 0 { 1 } else { 2 };
+    26 + rows as i32 * (8 + 4 * shift) + drawn_rows * 12 * bytes_per_row
+}